@@ -1,5 +1,8 @@
 
+use std::process;
+
 use clap::Parser;
+use util::data_models::{QueryOptions, FacetCount, TieBreak, Sort, TermExplanation};
 use util::read_query_files::make_query;
 
 #[derive(Parser, Debug)]
@@ -12,12 +15,71 @@ struct Args {
     query: String,
 
     #[clap(short, long, value_parser, default_value_t = 10)]
-    num_results: usize
+    num_results: usize,
+
+    /// Number of top results to skip, for paging through results
+    #[clap(long, value_parser, default_value_t = 0)]
+    offset: usize,
+
+    /// Orders results by relevance, name, mtime or size, optionally followed by :asc or :desc
+    #[clap(long, value_parser, default_value = "relevance")]
+    sort: Sort,
+
+    /// Orders results with equal weights by doc_id, name, mtime (or modified) or size
+    #[clap(long, value_parser, default_value = "doc_id")]
+    tie_break: TieBreak,
+
+    /// Also print counts of matching documents per extension, directory and year
+    #[clap(long, value_parser)]
+    facets: bool,
+
+    /// Also print how much each query term contributed to every result's weight
+    #[clap(long, value_parser)]
+    explain: bool,
+
+    /// Count every matching document, even when the top results can be found without visiting them all
+    #[clap(long, value_parser)]
+    exact_hits: bool
+}
+
+fn print_facet(name: &str, counts: &[FacetCount]) {
+    let counts: Vec<String> = counts.iter().map(|count| format!("{} ({})", count.value, count.count)).collect();
+    println!("{}: {}", name, counts.join(", "));
+}
+
+fn print_term_explanation(term: &TermExplanation) {
+    let raw_term_frequency = term.raw_term_frequency.map_or("?".to_string(), |tf| tf.to_string());
+    let doc_length = term.doc_length.map_or("?".to_string(), |length| length.to_string());
+    println!("    {}: {} = tf {:.4} ({} of {} tokens) * idf {:.4} ({} docs) * boost {:.4}",
+        term.term, term.weight, term.relative_term_frequency, raw_term_frequency, doc_length, term.idf, term.num_docs, term.boost);
 }
 
 fn main() {
     let args = Args::parse();
-    for (num, result) in make_query(&args.query, &args.directory, args.num_results).expect("Error reading files").iter().enumerate() {
-        println!("{}: {} (weight: {})", num + 1, result.name, result.weight);
+    let options = QueryOptions { num_results: args.num_results, offset: args.offset, facets: args.facets, sort: args.sort, tie_break: args.tie_break, explain: args.explain, exact_hits: args.exact_hits };
+    let query_results = make_query(&args.query, &args.directory, &options).unwrap_or_else(|error| {
+        eprintln!("Error reading files: {error}");
+        process::exit(1)
+    });
+    match query_results.total_hits_exact {
+        true => println!("{} results", query_results.total_hits),
+        false => println!("at least {} results", query_results.total_hits)
     }
-}
\ No newline at end of file
+    for (num, result) in query_results.results.iter().enumerate() {
+        println!("{}: {} (weight: {})", query_results.offset + num + 1, result.name, result.weight);
+        if let Some(title) = &result.title {
+            println!("    {}", title);
+        }
+        if let Some(snippet) = &result.snippet {
+            println!("    {}", snippet.marked("*", "*"));
+        }
+        if let Some(explanation) = &result.explanation {
+            explanation.terms.iter().for_each(print_term_explanation);
+        }
+    }
+    if let Some(facets) = &query_results.facets {
+        print_facet("Extensions", &facets.extensions);
+        print_facet("Directories", &facets.directories);
+        print_facet("Years", &facets.years);
+    }
+}
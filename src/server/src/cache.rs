@@ -0,0 +1,58 @@
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+use serde::Serialize;
+
+// Serialized responses for recent queries, keyed by the normalized query and its parameters.
+// Everything is dropped when the index generation changes, since results from a rebuilt index may differ
+pub struct ResultCache {
+    entries: LruCache<String, String>,
+    generation: u64,
+    hits: u64,
+    misses: u64
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+    pub generation: u64
+}
+
+impl ResultCache {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self { entries: LruCache::new(capacity), generation: 0, hits: 0, misses: 0 }
+    }
+
+    pub fn get(&mut self, key: &str, generation: u64) -> Option<String> {
+        if generation != self.generation {
+            self.entries.clear();
+            self.generation = generation;
+        }
+        let entry = self.entries.get(key).cloned();
+        match entry {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1
+        }
+        entry
+    }
+
+    pub fn insert(&mut self, key: String, response: String, generation: u64) {
+        if generation == self.generation {
+            self.entries.put(key, response);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+            capacity: self.entries.cap().get(),
+            generation: self.generation
+        }
+    }
+}
@@ -1,28 +1,91 @@
-use rocket::fs::NamedFile;
-use util::read_query_files::make_query;
-use web_result::get_web_results;
-use std::{env, path::{PathBuf, Path}};
+use rocket::{form, fs::NamedFile, http::Status, Request, State};
+use util::{read_query_files::{make_query, get_index_generation}, suggest::find_suggestion_candidates, parser::parse, query_parser::{parse_query, normalize_query}, data_models::{QueryOptions, TieBreak, Sort}};
+use cache::ResultCache;
+use search_counts::SearchCounts;
+use web_result::{get_web_response, get_web_suggestions, get_web_error, get_param, get_web_status_error};
+use std::{env, path::{PathBuf, Path}, sync::Mutex};
 
+mod cache;
+mod search_counts;
 mod web_result;
 
 #[macro_use] extern crate rocket;
 
+const MAX_QUERY_LOG_TERMS: usize = 10000;
+const QUERY_CACHE_SIZE: usize = 1000;
+
+// Counts how often each term has been searched for, used to rank suggestions by popularity
+struct QueryLog(Mutex<SearchCounts>);
+
+struct QueryCache(Mutex<ResultCache>);
+
+// Errors are returned as a json body along with their status code
+type ApiResponse = Result<String, (Status, String)>;
+
 #[get("/files/<file..>")]
 async fn files(file: PathBuf) -> Option<NamedFile> {
     let static_file_dir = env::var("STATIC_FILES_DIR").unwrap_or("static".to_string());
     NamedFile::open(Path::new(&static_file_dir).join(file)).await.ok()
 }
 
-#[get("/?<query>&<num_results>")]
-fn index(query: Option<String>, num_results: Option<usize>) -> String {
+#[get("/?<query>&<num_results>&<offset>&<facets>&<sort>&<tie_break>&<explain>&<exact_hits>")]
+#[allow(clippy::too_many_arguments)]
+fn index(query: Option<String>, num_results: form::Result<'_, usize>, offset: form::Result<'_, usize>, facets: form::Result<'_, bool>, sort: Option<String>, tie_break: Option<String>, explain: form::Result<'_, bool>, exact_hits: form::Result<'_, bool>, query_log: &State<QueryLog>, query_cache: &State<QueryCache>) -> ApiResponse {
     let query = query.unwrap_or("".to_string());
-    let num_results = num_results.unwrap_or(10);
+    let options = QueryOptions {
+        num_results: get_param(num_results, "num_results", 10)?,
+        offset: get_param(offset, "offset", 0)?,
+        facets: get_param(facets, "facets", false)?,
+        sort: sort.map_or(Ok(Sort::default()), |sort| sort.parse()).map_err(|error| get_web_error(&error))?,
+        tie_break: tie_break.map_or(Ok(TieBreak::DocId), |tie_break| tie_break.parse()).map_err(|error| get_web_error(&error))?,
+        explain: get_param(explain, "explain", false)?,
+        exact_hits: get_param(exact_hits, "exact_hits", false)?
+    };
     let query_file_dir = env::var("QUERY_FILES_DIR").unwrap_or("query_files".to_string());
-    let results = make_query(&query, &query_file_dir, num_results).unwrap();
-    serde_json::to_string(&get_web_results(&results)).unwrap()
+    let parsed_query = parse_query(&query).map_err(|error| get_web_error(&error))?;
+    let mut query_log = query_log.0.lock().unwrap();
+    for token in parse(&parsed_query.text) {
+        query_log.record(&token);
+    }
+    drop(query_log);
+    let cache_key = format!("{}\n{}\n{}\n{}\n{:?}\n{:?}\n{}\n{}", normalize_query(&query), options.num_results, options.offset,
+        options.facets, options.sort, options.tie_break, options.explain, options.exact_hits);
+    let generation = get_index_generation(&query_file_dir).map_err(|error| get_web_error(&error))?;
+    if let Some(response) = query_cache.0.lock().unwrap().get(&cache_key, generation) {
+        return Ok(response)
+    }
+    let results = make_query(&query, &query_file_dir, &options).map_err(|error| get_web_error(&error))?;
+    let response = serde_json::to_string(&get_web_response(&results)).unwrap();
+    query_cache.0.lock().unwrap().insert(cache_key, response.clone(), generation);
+    Ok(response)
+}
+
+#[get("/stats")]
+fn stats(query_cache: &State<QueryCache>) -> String {
+    serde_json::to_string(&query_cache.0.lock().unwrap().stats()).unwrap()
+}
+
+#[get("/suggest?<prefix>&<num_results>")]
+fn suggest(prefix: Option<String>, num_results: form::Result<'_, usize>, query_log: &State<QueryLog>) -> ApiResponse {
+    let prefix = prefix.unwrap_or("".to_string());
+    let num_results = get_param(num_results, "num_results", 10)?;
+    let query_file_dir = env::var("QUERY_FILES_DIR").unwrap_or("query_files".to_string());
+    // The terms file is read before taking the query log's lock, which is then only held to look up the candidates' counts
+    let candidates = find_suggestion_candidates(&prefix, &query_file_dir, num_results).map_err(|error| get_web_error(&error))?;
+    let suggestions = candidates.with_popularity(query_log.0.lock().unwrap().get_counts()).rank();
+    Ok(serde_json::to_string(&get_web_suggestions(&suggestions)).unwrap())
+}
+
+#[catch(default)]
+fn default_catcher(status: Status, _request: &Request) -> (Status, String) {
+    (status, get_web_status_error(status))
 }
 
 #[launch]
 fn rocket() -> _ {
-    rocket::build().mount("/", routes![index, files])
+    rocket::build()
+        .manage(QueryLog(Mutex::new(SearchCounts::new(MAX_QUERY_LOG_TERMS))))
+        .manage(QueryCache(Mutex::new(ResultCache::new(QUERY_CACHE_SIZE))))
+        .mount("/", routes![index, suggest, stats, files])
+        .register("/", catchers![default_catcher])
 }
@@ -0,0 +1,34 @@
+use util::hashtable::HashTable;
+
+// How often each term has been searched for, used to rank suggestions. Once it holds more than max_terms terms every
+// count is halved, dropping terms searched for once, until it's back to half that, so memory stays bounded and old searches fade
+pub struct SearchCounts {
+    counts: HashTable<usize>,
+    max_terms: usize
+}
+
+impl SearchCounts {
+    pub fn new(max_terms: usize) -> Self {
+        Self { counts: HashTable::new(max_terms * 2), max_terms }
+    }
+
+    pub fn record(&mut self, term: &str) {
+        self.counts.insert_combine(term, 1);
+        if self.counts.len() > self.max_terms {
+            while self.counts.len() > self.max_terms / 2 {
+                self.decay();
+            }
+        }
+    }
+
+    fn decay(&mut self) {
+        self.counts = self.counts.iter()
+            .filter(|entry| entry.value > 1)
+            .map(|entry| (entry.key.as_str(), entry.value / 2))
+            .collect();
+    }
+
+    pub fn get_counts(&self) -> &HashTable<usize> {
+        &self.counts
+    }
+}
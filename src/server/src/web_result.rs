@@ -1,17 +1,179 @@
+use std::io::ErrorKind;
+
+use rocket::{form::{self, error::ErrorKind as FormErrorKind}, http::{Status, StatusClass}};
 use serde::Serialize;
-use util::data_models::NamedResult;
+use util::error::Error;
+use util::data_models::{NamedResult, Suggestion, Snippet, QueryResults, Facets, FacetCount, Explanation};
+
+#[derive(Serialize)]
+pub struct WebResponse<'a> {
+    pub total_hits: usize,
+    pub total_hits_exact: bool,
+    pub offset: usize,
+    pub num_results: usize,
+    pub results: Vec<WebResult<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub facets: Option<WebFacets<'a>>
+}
+
+#[derive(Serialize)]
+pub struct WebFacets<'a> {
+    pub extensions: Vec<WebFacetCount<'a>>,
+    pub directories: Vec<WebFacetCount<'a>>,
+    pub years: Vec<WebFacetCount<'a>>
+}
+
+#[derive(Serialize)]
+pub struct WebFacetCount<'a> {
+    pub value: &'a str,
+    pub count: usize
+}
 
 #[derive(Serialize)]
 pub struct WebResult<'a> {
     pub ranking: usize,
     pub file_name: &'a str,
-    pub weight: usize
+    pub weight: usize,
+    pub snippet: Option<Vec<WebSnippetFragment<'a>>>,
+    pub size: u64,
+    pub modified: u64,
+    pub content_type: &'a str,
+    pub encoding: &'a str,
+    pub title: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<Vec<WebTermExplanation<'a>>>
+}
+
+#[derive(Serialize)]
+pub struct WebTermExplanation<'a> {
+    pub term: &'a str,
+    pub weight: usize,
+    pub raw_term_frequency: Option<usize>,
+    pub relative_term_frequency: f64,
+    pub num_docs: usize,
+    pub idf: f64,
+    pub doc_length: Option<usize>,
+    pub boost: f64
+}
+
+#[derive(Serialize)]
+pub struct WebSnippetFragment<'a> {
+    pub text: &'a str,
+    pub matched: bool
+}
+
+#[derive(Serialize)]
+pub struct WebError<'a> {
+    pub error: &'a str,
+    pub message: String
+}
+
+#[derive(Serialize)]
+pub struct WebSuggestion<'a> {
+    pub term: &'a str,
+    pub num_docs: usize,
+    pub popularity: usize
 }
 
-pub fn get_web_results(sorted_results: &Vec<NamedResult>) -> Vec<WebResult> {
+pub fn get_web_response(query_results: &QueryResults) -> WebResponse<'_> {
+    WebResponse {
+        total_hits: query_results.total_hits,
+        total_hits_exact: query_results.total_hits_exact,
+        offset: query_results.offset,
+        num_results: query_results.results.len(),
+        results: get_web_results(&query_results.results, query_results.offset),
+        facets: query_results.facets.as_ref().map(get_web_facets)
+    }
+}
+
+fn get_web_facets(facets: &Facets) -> WebFacets<'_> {
+    WebFacets {
+        extensions: get_web_facet_counts(&facets.extensions),
+        directories: get_web_facet_counts(&facets.directories),
+        years: get_web_facet_counts(&facets.years)
+    }
+}
+
+fn get_web_facet_counts(counts: &[FacetCount]) -> Vec<WebFacetCount<'_>> {
+    counts.iter().map(|count| WebFacetCount { value: &count.value, count: count.count }).collect()
+}
+
+fn get_web_results(sorted_results: &[NamedResult], offset: usize) -> Vec<WebResult<'_>> {
     let mut json_results = vec![];
     for (rank, result) in sorted_results.iter().enumerate() {
-        json_results.push(WebResult { ranking: rank + 1, file_name: &result.name, weight: result.weight })
+        json_results.push(WebResult {
+            ranking: offset + rank + 1,
+            file_name: &result.name,
+            weight: result.weight,
+            snippet: result.snippet.as_ref().map(get_web_snippet),
+            size: result.size,
+            modified: result.modified,
+            content_type: &result.content_type,
+            encoding: &result.encoding,
+            title: result.title.as_deref(),
+            explanation: result.explanation.as_ref().map(get_web_explanation)
+        })
     }
     json_results
 }
+
+fn get_web_snippet(snippet: &Snippet) -> Vec<WebSnippetFragment<'_>> {
+    snippet.fragments.iter().map(|fragment| WebSnippetFragment { text: &fragment.text, matched: fragment.matched }).collect()
+}
+
+fn get_web_explanation(explanation: &Explanation) -> Vec<WebTermExplanation<'_>> {
+    explanation.terms.iter().map(|term| WebTermExplanation {
+        term: &term.term,
+        weight: term.weight,
+        raw_term_frequency: term.raw_term_frequency,
+        relative_term_frequency: term.relative_term_frequency,
+        num_docs: term.num_docs,
+        idf: term.idf,
+        doc_length: term.doc_length,
+        boost: term.boost
+    }).collect()
+}
+
+// Bad queries are the client's fault, a missing index means the server isn't ready yet, and anything else is a server error
+pub fn get_web_error(error: &Error) -> (Status, String) {
+    let (status, kind) = match error {
+        Error::QuerySyntax(_) => (Status::BadRequest, "query_syntax"),
+        Error::Io(io_error) if io_error.kind() == ErrorKind::NotFound => (Status::ServiceUnavailable, "index_not_found"),
+        Error::Io(_) => (Status::InternalServerError, "io"),
+        Error::CorruptIndex(_) => (Status::InternalServerError, "corrupt_index"),
+        Error::IncompatibleFormat(_) => (Status::InternalServerError, "incompatible_format")
+    };
+    let body = WebError { error: kind, message: error.to_string() };
+    (status, serde_json::to_string(&body).unwrap_or_default())
+}
+
+// Parameters that are left out take their default, but ones that don't parse are rejected rather than silently ignored
+pub fn get_param<T>(param: form::Result<'_, T>, name: &str, default: T) -> Result<T, (Status, String)> {
+    match param {
+        Ok(value) => Ok(value),
+        Err(errors) if errors.iter().all(|error| matches!(error.kind, FormErrorKind::Missing)) => Ok(default),
+        Err(errors) => {
+            let reasons: Vec<String> = errors.iter().map(|error| error.kind.to_string()).collect();
+            Err(get_web_error(&Error::QuerySyntax(format!("{name}: {}", reasons.join(", ")))))
+        }
+    }
+}
+
+// Errors Rocket raises itself, such as unmatched routes and handler panics, get the same json body as query errors
+pub fn get_web_status_error(status: Status) -> String {
+    let kind = match status.class() {
+        _ if status == Status::NotFound => "not_found",
+        StatusClass::ServerError => "internal",
+        _ => "bad_request"
+    };
+    let body = WebError { error: kind, message: status.reason_lossy().to_string() };
+    serde_json::to_string(&body).unwrap_or_default()
+}
+
+pub fn get_web_suggestions(suggestions: &[Suggestion]) -> Vec<WebSuggestion<'_>> {
+    let mut json_suggestions = vec![];
+    for suggestion in suggestions {
+        json_suggestions.push(WebSuggestion { term: &suggestion.term, num_docs: suggestion.num_docs, popularity: suggestion.popularity })
+    }
+    json_suggestions
+}
@@ -1,24 +1,20 @@
-use std::sync::{Mutex, Arc};
-use std::{fs, io::Error};
+use std::sync::{Mutex, Arc, atomic::{AtomicUsize, Ordering}};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, mem::size_of, path::{Path, PathBuf}, process};
 
 use clap::Parser;
 use threadpool::ThreadPool;
-use util::data_models::{GlobHTBucket, MapRecord};
-use encoding::{all::ISO_8859_1, Encoding, DecoderTrap};
+use util::data_models::{GlobHTBucket, DocFrequency, MapRecord, DocStatus, IndexMetadata, IndexStats, PostingsOrder, PruningPolicy};
 
-use util::parser::parse;
-use util::hashtable::HashTable;
+use util::error::Error;
+use util::documents::{read_latin1_file, read_document, stream_document, detect_content_type, extract_title, Document};
+use util::parser::{parse, ChunkParser};
+use util::hashtable::{HashTable, TableEntry};
+use util::sharded_hashtable::ShardedHashTable;
 use util::constants::*;
-use util::write_query_files::write_output_files;
-
-fn read_latin1_file(file_path: &str) -> Result<String, Error> {
-    let bytes = fs::read(file_path)?;
-    let contents = match ISO_8859_1.decode(&bytes, DecoderTrap::Ignore) {
-        Ok(string) => string,
-        Err(str) => str.into_owned()
-    };
-    Ok(contents)
-}
+use util::runs::write_run;
+use util::store_spool::StoreSpool;
+use util::write_query_files::{write_output_files, write_output_files_from_runs, write_stats};
 
 fn create_stop_ht(stop_path: &str) -> Result<HashTable<usize>, Error> {
     let stop_words = parse(&read_latin1_file(stop_path)?);
@@ -29,28 +25,183 @@ fn create_stop_ht(stop_path: &str) -> Result<HashTable<usize>, Error> {
     Ok(stop_ht)
 }
 
-fn tokenize_file(stop_ht: &HashTable<usize>, file_path: &str) -> Result<(HashTable<usize>, usize), Error> {
+// Subdirectories are included, with each document named by its path relative to indir.
+// Symlinked directories are skipped rather than followed, since they can lead back into indir and never end
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() { collect_files(&path, files)? }
+        else if !path.is_dir() { files.push(path) }
+    }
+    Ok(())
+}
+
+fn get_modified_time(file_metadata: &fs::Metadata) -> u64 {
+    file_metadata.modified().ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs())
+}
+
+fn set_doc_details(map_files: &Mutex<Vec<MapRecord>>, doc_id: usize, document: &Document, token_count: usize) {
+    let title = extract_title(&document.contents);
+    let mut map_files = map_files.lock().unwrap();
+    let record = &mut map_files[doc_id];
+    record.content_type = detect_content_type(&record.file_name, &document.contents).to_string();
+    record.encoding = document.encoding.to_string();
+    record.title = title;
+    record.num_tokens = token_count;
+}
+
+fn set_doc_failed(map_files: &Mutex<Vec<MapRecord>>, doc_id: usize, error: &Error) {
+    map_files.lock().unwrap()[doc_id].status = DocStatus::Failed(error.to_string());
+}
+
+fn tokenize_file(stop_ht: &HashTable<usize>, map_files: &Mutex<Vec<MapRecord>>, store_spool: Option<&StoreSpool>, file_path: &str, file_name: &str, doc_id: usize) -> Result<(HashTable<usize>, usize), Error> {
     let mut doc_ht: HashTable<usize> = HashTable::new(DOC_HT_SIZE);
-    let file_contents = read_latin1_file(file_path)?;
-    let tokens = parse(&file_contents);
     let mut token_count: usize = 0;
-    for token in tokens {
-        if !stop_ht.intable(&token) {
-            doc_ht.insert_combine(token.as_str(), 1);
-            token_count += 1;
+    let mut count_tokens = |tokens: Vec<String>| {
+        for token in tokens {
+            if !stop_ht.intable(&token) {
+                doc_ht.insert_combine(token.as_str(), 1);
+                token_count += 1;
+            }
         }
+    };
+    // Large files are decoded and tokenized a chunk at a time, so they never have to fit in memory
+    let document = if fs::metadata(file_path)?.len() > STREAM_THRESHOLD_BYTES {
+        let mut parser = ChunkParser::new();
+        let document = stream_document(file_path, |chunk| count_tokens(parser.parse_chunk(chunk)))?;
+        count_tokens(parser.finish());
+        document
+    }
+    else {
+        let document = read_document(file_path)?;
+        count_tokens(parse(&document.contents));
+        document
+    };
+    set_doc_details(map_files, doc_id, &document, token_count);
+    if let Some(store_spool) = store_spool {
+        store_spool.add(file_name, &document.contents)?;
     }
     Ok((doc_ht, token_count))
 }
 
-fn insert_doc_into_glob(glob_ht: Arc<Mutex<HashTable<GlobHTBucket>>>, doc_ht: HashTable<usize>, token_count: usize, doc_id: usize) {
-    for bucket in doc_ht.get_buckets() {
-        if let Some(entry) = bucket  {
-            let raw_term_frequency: usize = entry.value;
-            let relative_term_frequency: f64 = raw_term_frequency as f64 / token_count as f64;
-            let file_record = GlobHTBucket::new(doc_id, raw_term_frequency, relative_term_frequency);
-            let mut glob_ht = glob_ht.lock().unwrap();
-            glob_ht.insert_combine(entry.key.as_str(), file_record);
+// Writes the collected postings out to a run whenever they pass the memory budget, leaving the table empty for the rest.
+// Memory use is estimated from the postings added, counting every term as new
+struct Spill {
+    outdir: String,
+    budget: usize,
+    used: AtomicUsize,
+    runs: Mutex<Vec<String>>
+}
+
+impl Spill {
+    fn new(outdir: &str, budget: usize) -> Self {
+        Self { outdir: outdir.to_string(), budget, used: AtomicUsize::new(0), runs: Mutex::new(vec![]) }
+    }
+
+    fn add(&self, glob_ht: &ShardedHashTable<GlobHTBucket>, bytes: usize) -> Result<(), Error> {
+        if self.used.fetch_add(bytes, Ordering::Relaxed) + bytes < self.budget { return Ok(()) }
+        let mut runs = self.runs.lock().unwrap();
+        // Another thread may have written a run while this one waited for the lock
+        if self.used.load(Ordering::Relaxed) < self.budget { return Ok(()) }
+        self.used.store(0, Ordering::Relaxed);
+        self.write_run(glob_ht, &mut runs)
+    }
+
+    fn write_run(&self, glob_ht: &ShardedHashTable<GlobHTBucket>, runs: &mut Vec<String>) -> Result<(), Error> {
+        let path = format!("{}/run{}", self.outdir, runs.len());
+        write_run(&path, &glob_ht.take_table(GLOB_HT_SIZE))?;
+        runs.push(path);
+        Ok(())
+    }
+
+    // Writes whatever is left as the last run, unless nothing was ever spilled, in which case the index is written from memory
+    fn finish(&self, glob_ht: &ShardedHashTable<GlobHTBucket>) -> Result<Vec<String>, Error> {
+        let mut runs = self.runs.lock().unwrap();
+        if !runs.is_empty() {
+            self.write_run(glob_ht, &mut runs)?;
+        }
+        Ok(runs.clone())
+    }
+
+    fn remove_runs(&self) -> Result<(), Error> {
+        for run in self.runs.lock().unwrap().drain(..) {
+            fs::remove_file(run)?;
+        }
+        Ok(())
+    }
+}
+
+fn insert_doc_into_glob(glob_ht: &ShardedHashTable<GlobHTBucket>, spill: Option<&Spill>, doc_ht: HashTable<usize>, token_count: usize, doc_id: usize) -> Result<(), Error> {
+    let mut entries = Vec::with_capacity(doc_ht.len());
+    let mut bytes = 0;
+    for entry in doc_ht.iter() {
+        let raw_term_frequency: usize = entry.value;
+        let relative_term_frequency: f64 = raw_term_frequency as f64 / token_count as f64;
+        entries.push((entry.key.as_str(), GlobHTBucket::new(doc_id, raw_term_frequency, relative_term_frequency)));
+        bytes += entry.key.len() + size_of::<DocFrequency>() + size_of::<TableEntry<GlobHTBucket>>();
+    }
+    glob_ht.insert_combine_batch(entries);
+    match spill {
+        Some(spill) => spill.add(glob_ht, bytes),
+        None => Ok(())
+    }
+}
+
+// Counts documents as they're tokenized, logging how far along indexing is every PROGRESS_INTERVAL_SECS
+struct Progress {
+    start: Instant,
+    docs_total: usize,
+    docs_indexed: AtomicUsize,
+    docs_failed: AtomicUsize,
+    tokens: AtomicUsize,
+    last_log: Mutex<Instant>
+}
+
+impl Progress {
+    fn new(docs_total: usize) -> Self {
+        let start = Instant::now();
+        Self { start, docs_total, docs_indexed: AtomicUsize::new(0), docs_failed: AtomicUsize::new(0), tokens: AtomicUsize::new(0), last_log: Mutex::new(start) }
+    }
+
+    fn indexed(&self, token_count: usize) {
+        self.docs_indexed.fetch_add(1, Ordering::Relaxed);
+        self.tokens.fetch_add(token_count, Ordering::Relaxed);
+        self.log_if_due();
+    }
+
+    fn failed(&self) {
+        self.docs_failed.fetch_add(1, Ordering::Relaxed);
+        self.log_if_due();
+    }
+
+    // Threads that find another one logging carry on rather than wait for it
+    fn log_if_due(&self) {
+        let Ok(mut last_log) = self.last_log.try_lock() else { return };
+        if last_log.elapsed() < Duration::from_secs(PROGRESS_INTERVAL_SECS) { return }
+        *last_log = Instant::now();
+        let docs_done = self.docs_indexed.load(Ordering::Relaxed) + self.docs_failed.load(Ordering::Relaxed);
+        eprintln!("Tokenized {}/{} documents ({:.0}%), {} failed, {:.0} documents/s",
+            docs_done, self.docs_total, 100.0 * docs_done as f64 / self.docs_total as f64,
+            self.docs_failed.load(Ordering::Relaxed), self.docs_per_second());
+    }
+
+    fn docs_per_second(&self) -> f64 {
+        let docs_done = self.docs_indexed.load(Ordering::Relaxed) + self.docs_failed.load(Ordering::Relaxed);
+        docs_done as f64 / self.start.elapsed().as_secs_f64()
+    }
+
+    fn get_stats(&self) -> IndexStats {
+        IndexStats {
+            docs_total: self.docs_total,
+            docs_indexed: self.docs_indexed.load(Ordering::Relaxed),
+            docs_failed: self.docs_failed.load(Ordering::Relaxed),
+            tokens: self.tokens.load(Ordering::Relaxed),
+            tokenize_seconds: self.start.elapsed().as_secs_f64(),
+            docs_per_second: self.docs_per_second(),
+            ..Default::default()
         }
     }
 }
@@ -68,28 +219,147 @@ struct Args {
     stop_path: String,
 
     #[clap(short, long, value_parser, default_value_t = num_cpus::get())]
-    num_threads: usize
+    num_threads: usize,
+
+    /// Keep a compressed copy of every document's title, url and text in the index
+    #[clap(long, value_parser)]
+    store_docs: bool,
+
+    /// Write each term's postings from highest to lowest weight, making ranked queries faster but required terms slower
+    #[clap(long, value_parser)]
+    impact_ordered: bool,
+
+    /// Write postings out to sorted runs in outdir whenever they take about this many megabytes, merging them once
+    /// tokenizing is done, for corpora whose index doesn't fit in memory
+    #[clap(long, value_parser)]
+    memory_budget: Option<usize>,
+
+    /// The fraction of documents that can fail to be read before the tokenizer gives up without writing an index.
+    /// Failed documents are always left out of the index and listed in the stats file
+    #[clap(long, value_parser, default_value_t = 0.01)]
+    max_error_rate: f64,
+
+    /// Leave out terms found in fewer than this many documents
+    #[clap(long, value_parser, default_value_t = PruningPolicy::default().min_docs)]
+    min_docs: usize,
+
+    /// Leave out terms that appear fewer than this many times across all documents
+    #[clap(long, value_parser, default_value_t = PruningPolicy::default().min_occurrences)]
+    min_occurrences: usize,
+
+    /// Keep terms containing a digit however rare they are, such as ticket numbers and error codes
+    #[clap(long, value_parser)]
+    keep_numbers: bool,
+
+    /// Keep every term, including ones that appear once in one document
+    #[clap(long, value_parser, conflicts_with_all = &["min-docs", "min-occurrences", "keep-numbers"])]
+    keep_all_terms: bool
 }
 
 fn main() {
     let args = Args::parse();
-    let glob_ht: Arc<Mutex<HashTable<GlobHTBucket>>> = Arc::new(Mutex::new(HashTable::new(GLOB_HT_SIZE)));
+    // Sharded so threads adding different terms don't wait on each other, and merged into one table once all documents are in
+    let glob_ht: Arc<ShardedHashTable<GlobHTBucket>> = Arc::new(ShardedHashTable::new(GLOB_HT_SIZE, args.num_threads * GLOB_HT_SHARDS_PER_THREAD));
     let stop_ht: Arc<HashTable<usize>> = Arc::new(create_stop_ht(&args.stop_path).expect("Error opening stopfile"));
-    let mut map_files: Vec<MapRecord> = vec![];
+    let map_files: Arc<Mutex<Vec<MapRecord>>> = Arc::new(Mutex::new(vec![]));
+    // Documents' text is kept for the document store as they're read, rather than reading indir again afterwards
+    let store_spool: Option<Arc<StoreSpool>> = args.store_docs.then(|| Arc::new(StoreSpool::create(&format!("{}/store_spool", args.outdir)).expect("Could not create store spool")));
+    let spill: Option<Arc<Spill>> = args.memory_budget.map(|megabytes| Arc::new(Spill::new(&args.outdir, megabytes * 1024 * 1024)));
     let pool = ThreadPool::new(args.num_threads);
-    for (doc_id, file_path) in fs::read_dir(&args.indir).expect("Could not read indir").enumerate() {
-        let file_name = file_path.as_ref().unwrap().file_name().into_string().unwrap();
-        let file_path_str = file_path.unwrap().path().to_str().unwrap().to_owned();
-        map_files.push(MapRecord { doc_id: doc_id, file_name: file_name.clone() });
+    let mut file_paths = vec![];
+    collect_files(Path::new(&args.indir), &mut file_paths).expect("Could not read indir");
+    file_paths.sort();
+    let progress = Arc::new(Progress::new(file_paths.len()));
+    for (doc_id, file_path) in file_paths.into_iter().enumerate() {
+        let file_name = file_path.strip_prefix(&args.indir).unwrap().to_str().unwrap().to_owned();
+        let file_path_str = file_path.to_str().unwrap().to_owned();
+        let file_metadata = fs::metadata(&file_path).ok();
+        let size = file_metadata.as_ref().map_or(0, |file_metadata| file_metadata.len());
+        let modified = file_metadata.as_ref().map_or(0, get_modified_time);
+        map_files.lock().unwrap().push(MapRecord::new(doc_id, file_name.clone(), size, modified));
         let glob_ht_clone = Arc::clone(&glob_ht);
         let stop_ht_clone = Arc::clone(&stop_ht);
+        let map_files_clone = Arc::clone(&map_files);
+        let spill_clone = spill.clone();
+        let store_spool_clone = store_spool.clone();
+        let progress_clone = Arc::clone(&progress);
         pool.execute(move || {
-            match tokenize_file(&stop_ht_clone, &file_path_str) {
-                Ok((doc_ht, token_count)) => insert_doc_into_glob(glob_ht_clone, doc_ht, token_count, doc_id),
-                Err(e) => println!("Could not read file {}: {}", &file_name, e),
+            match tokenize_file(&stop_ht_clone, &map_files_clone, store_spool_clone.as_deref(), &file_path_str, &file_name, doc_id) {
+                Ok((doc_ht, token_count)) => {
+                    insert_doc_into_glob(&glob_ht_clone, spill_clone.as_deref(), doc_ht, token_count, doc_id).expect("Could not write run");
+                    progress_clone.indexed(token_count);
+                }
+                Err(e) => {
+                    eprintln!("Could not read file {}: {}", &file_name, e);
+                    set_doc_failed(&map_files_clone, doc_id, &e);
+                    progress_clone.failed();
+                }
             };
         });
     }
     pool.join();
-    write_output_files(&args.outdir, &glob_ht.lock().unwrap(), &map_files).unwrap();
+    let store_spool = store_spool.map(|store_spool| Arc::into_inner(store_spool).expect("All tokenizing threads have finished"));
+    if pool.panic_count() > 0 {
+        eprintln!("Tokenizing failed, no index was written");
+        process::exit(1);
+    }
+    let failures = map_files.lock().unwrap().iter()
+        .filter_map(|record| match &record.status {
+            DocStatus::Failed(error) => Some((record.file_name.clone(), error.clone())),
+            DocStatus::Indexed => None
+        })
+        .collect();
+    let tokenize_stats = IndexStats { failures, ..progress.get_stats() };
+    eprintln!("Tokenized {} documents in {:.1}s, {} failed", tokenize_stats.docs_total, tokenize_stats.tokenize_seconds, tokenize_stats.docs_failed);
+    let error_rate = tokenize_stats.docs_failed as f64 / tokenize_stats.docs_total.max(1) as f64;
+    if error_rate > args.max_error_rate {
+        if let Some(spill) = &spill { spill.remove_runs().expect("Could not remove run") }
+        if let Some(store_spool) = store_spool { store_spool.remove().expect("Could not remove store spool") }
+        write_stats(&args.outdir, &tokenize_stats).expect("Could not write stats");
+        eprintln!("{:.1}% of documents failed, over the maximum of {:.1}%, no index was written", error_rate * 100.0, args.max_error_rate * 100.0);
+        process::exit(1);
+    }
+    let indir = fs::canonicalize(Path::new(&args.indir)).expect("Could not read indir");
+    let postings_order = if args.impact_ordered { PostingsOrder::Impact } else { PostingsOrder::DocId };
+    let generation = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_millis() as u64);
+    let pruning = if args.keep_all_terms { PruningPolicy::keep_all() }
+        else { PruningPolicy { min_docs: args.min_docs, min_occurrences: args.min_occurrences, keep_numbers: args.keep_numbers } };
+    let indir = indir.to_str().map(|dir| dir.to_string());
+    // Stored documents' urls are made from indir, so the store is left out when its path isn't utf-8
+    let store_spool = match store_spool {
+        Some(store_spool) if indir.is_none() => {
+            eprintln!("indir isn't a utf-8 path, so no document store was written");
+            store_spool.remove().expect("Could not remove store spool");
+            None
+        }
+        store_spool => store_spool
+    };
+    let metadata = IndexMetadata { indir, doc_store: store_spool.is_some(), postings_order, generation, pruning, ..Default::default() };
+    let runs = spill.as_ref().map_or(Ok(vec![]), |spill| spill.finish(&glob_ht)).expect("Could not write run");
+    let written = if runs.is_empty() {
+        let glob_ht = Arc::into_inner(glob_ht).expect("All tokenizing threads have finished").into_table(GLOB_HT_SIZE);
+        write_output_files(&args.outdir, &glob_ht, &map_files.lock().unwrap(), &metadata, store_spool)
+    }
+    else {
+        eprintln!("Merging {} runs", runs.len());
+        write_output_files_from_runs(&args.outdir, &runs, &map_files.lock().unwrap(), &metadata, store_spool)
+    };
+    // Runs and the store spool are only scratch files, so they're removed whether or not the index could be written
+    if let Some(spill) = &spill { spill.remove_runs().expect("Could not remove run") }
+    let term_stats = written.unwrap_or_else(|error| {
+        let _ = fs::remove_file(format!("{}/store_spool", args.outdir));
+        eprintln!("Could not write index: {error}");
+        process::exit(1);
+    });
+    let stats = IndexStats {
+        generation,
+        unique_terms: term_stats.unique_terms,
+        rare_terms_pruned: term_stats.rare_terms_pruned,
+        runs: runs.len(),
+        total_seconds: progress.start.elapsed().as_secs_f64(),
+        ..tokenize_stats
+    };
+    write_stats(&args.outdir, &stats).expect("Could not write stats");
+    eprintln!("Indexed {} documents with {} terms, pruning {} rare terms, in {:.1}s",
+        stats.docs_indexed, stats.unique_terms, stats.rare_terms_pruned, stats.total_seconds);
 }
@@ -0,0 +1,52 @@
+use std::{env, fs, path::{Path, PathBuf}, process::Command};
+
+use util::data_models::{QueryOptions, Sort, TieBreak};
+use util::read_query_files::make_query;
+
+// Writes a small corpus, including a link the parser turns into an empty term
+fn write_corpus(indir: &Path) {
+    fs::create_dir_all(indir).unwrap();
+    for doc in 0..50 {
+        let text = format!("rust doc {doc} alpha beta w{} <b>E</b>lephants we'll see w{}", doc % 7, doc % 3);
+        fs::write(indir.join(format!("doc{doc:02}.txt")), text).unwrap();
+    }
+    fs::write(indir.join("link.txt"), "visit http://... now rust").unwrap();
+}
+
+fn build_index(indir: &Path, outdir: &Path, stop_path: &Path, extra_args: &[&str]) {
+    fs::create_dir_all(outdir).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_tokenizer"))
+        .args(["--indir", indir.to_str().unwrap(), "--outdir", outdir.to_str().unwrap(), "--stop-path", stop_path.to_str().unwrap(), "--keep-all-terms"])
+        .args(extra_args)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+fn query(outdir: &Path, query: &str, facets: bool) -> (Vec<(String, usize)>, usize, bool) {
+    let options = QueryOptions { num_results: 100, offset: 0, facets, sort: Sort::default(), tie_break: TieBreak::DocId, explain: false, exact_hits: false };
+    let results = make_query(query, outdir.to_str().unwrap(), &options).unwrap();
+    let names = results.results.into_iter().map(|result| (result.name, result.weight)).collect();
+    (names, results.total_hits, results.total_hits_exact)
+}
+
+// The dict and postings written from runs aren't byte for byte the same as the in-memory ones, but they have to answer queries the same
+#[test]
+fn runs_index_the_same_as_memory() {
+    let dir: PathBuf = env::temp_dir().join(format!("tokenizer-runs-{}", std::process::id()));
+    let indir = dir.join("in");
+    let stop_path = dir.join("stop.txt");
+    write_corpus(&indir);
+    fs::write(&stop_path, "").unwrap();
+    let memory_dir = dir.join("memory");
+    let runs_dir = dir.join("runs");
+    build_index(&indir, &memory_dir, &stop_path, &[]);
+    build_index(&indir, &runs_dir, &stop_path, &["--memory-budget", "0"]);
+    assert!(fs::read_dir(&runs_dir).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with("run")));
+    for text in ["rust", "visit now", "w3 alpha", "elephants we'll", "+w1 w2", "doc 7", "nothing"] {
+        for facets in [false, true] {
+            assert_eq!(query(&memory_dir, text, facets), query(&runs_dir, text, facets), "query {text:?}");
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
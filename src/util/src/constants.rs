@@ -1,4 +1,20 @@
 pub const DOC_HT_SIZE: usize = 50000;
 pub const GLOB_HT_SIZE: usize = 350000;
+pub const GLOB_HT_SHARDS_PER_THREAD: usize = 4;
 pub const WEIGHT_MULTIPLIER: f64 = 100000000.0;
 pub const TERM_LENGTH: usize = 16;
+pub const SNIPPET_LENGTH: usize = 24;
+pub const DOCS_PER_STORE_BLOCK: usize = 16;
+pub const MAX_TITLE_LENGTH: usize = 100;
+pub const POSTINGS_PER_SKIP: usize = 64;
+pub const MAX_LOAD_FACTOR: f64 = 0.5;
+pub const FX_HASH_SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+pub const STREAM_THRESHOLD_BYTES: u64 = 64 * 1024 * 1024;
+pub const STREAM_CHUNK_BYTES: usize = 1024 * 1024;
+pub const MAX_CARRY_BYTES: usize = 64 * 1024;
+pub const MAX_SNIPPET_SOURCE_BYTES: usize = 1024 * 1024;
+pub const MAX_STORED_TEXT_BYTES: usize = 1024 * 1024;
+pub const PROGRESS_INTERVAL_SECS: u64 = 5;
+pub const INDEX_FILES: [&str; 9] = ["dict", "post", "skips", "map", "terms", "sizes", "metadata", "store", "store_index"];
+pub const MAX_SUGGEST_SCAN_LINES: usize = 10000;
+pub const MAX_BOOST: f64 = 1000.0;
@@ -1,11 +1,155 @@
-use std::{ops::AddAssign, cmp};
+use std::{ops::AddAssign, cmp, str::FromStr, collections::BTreeMap};
 use std::cmp::Ordering;
 use serde::{Deserialize, Serialize};
-use crate::{constants::*, hashtable::HashTable};
+use crate::{constants::*, hashtable::{HashTable, HashFunction}, error::Error};
 
 pub struct NamedResult {
+    pub doc_id: usize,
     pub name: String,
-    pub weight: usize
+    pub weight: usize,
+    pub snippet: Option<Snippet>,
+    pub size: u64,
+    pub modified: u64,
+    pub content_type: String,
+    pub encoding: String,
+    pub title: Option<String>,
+    pub num_tokens: usize,
+    pub explanation: Option<Explanation>
+}
+
+// How much one query term added to a result's weight, and the values that weight was calculated from
+pub struct TermExplanation {
+    pub term: String,
+    pub weight: usize,
+    pub raw_term_frequency: Option<usize>,
+    pub relative_term_frequency: f64,
+    pub num_docs: usize,
+    pub idf: f64,
+    pub doc_length: Option<usize>,
+    pub boost: f64
+}
+
+pub struct Explanation {
+    pub terms: Vec<TermExplanation>
+}
+
+pub struct QueryOptions {
+    pub num_results: usize,
+    pub offset: usize,
+    pub facets: bool,
+    pub sort: Sort,
+    pub tie_break: TieBreak,
+    pub explain: bool,
+    // Counts every match even when the top results were found without visiting them all
+    pub exact_hits: bool
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SortField {
+    Relevance,
+    Name,
+    Modified,
+    Size
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Sort {
+    pub field: SortField,
+    pub descending: bool
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Self { field: SortField::Relevance, descending: true }
+    }
+}
+
+// Takes the form "field" or "field:asc"/"field:desc". Names sort ascending by default and everything else descending
+impl FromStr for Sort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, order) = match s.split_once(':') {
+            Some((field, order)) => (field, Some(order)),
+            None => (s, None)
+        };
+        let field = match field {
+            "relevance" => SortField::Relevance,
+            "name" => SortField::Name,
+            "mtime" | "modified" => SortField::Modified,
+            "size" => SortField::Size,
+            _ => return Err(Error::QuerySyntax(format!("unknown sort field {field}, expected one of relevance, name, mtime, size")))
+        };
+        let descending = match order {
+            Some("asc") => false,
+            Some("desc") => true,
+            None => field != SortField::Name,
+            Some(order) => return Err(Error::QuerySyntax(format!("unknown sort order {order}, expected asc or desc")))
+        };
+        Ok(Self { field, descending })
+    }
+}
+
+// Orders results with equal weights, always ascending and falling back to doc id
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TieBreak {
+    DocId,
+    Name,
+    Modified,
+    Size
+}
+
+impl FromStr for TieBreak {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "doc_id" => Ok(TieBreak::DocId),
+            "name" => Ok(TieBreak::Name),
+            "mtime" | "modified" => Ok(TieBreak::Modified),
+            "size" => Ok(TieBreak::Size),
+            _ => Err(Error::QuerySyntax(format!("unknown tie break {s}, expected one of doc_id, name, mtime, size")))
+        }
+    }
+}
+
+pub struct QueryResults {
+    pub results: Vec<NamedResult>,
+    pub total_hits: usize,
+    // False when the top results were found without visiting every match, making total_hits a lower bound
+    pub total_hits_exact: bool,
+    pub offset: usize,
+    pub facets: Option<Facets>
+}
+
+pub struct FacetCount {
+    pub value: String,
+    pub count: usize
+}
+
+// Counts are taken over every matching document, not just the returned results
+pub struct Facets {
+    pub extensions: Vec<FacetCount>,
+    pub directories: Vec<FacetCount>,
+    pub years: Vec<FacetCount>
+}
+
+pub struct SnippetFragment {
+    pub text: String,
+    pub matched: bool
+}
+
+pub struct Snippet {
+    pub fragments: Vec<SnippetFragment>
+}
+
+impl Snippet {
+    pub fn marked(&self, open: &str, close: &str) -> String {
+        self.fragments.iter().fold(String::new(), |text, fragment| match fragment.matched {
+            true => format!("{text}{open}{}{close}", fragment.text),
+            false => format!("{text}{}", fragment.text)
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -15,99 +159,294 @@ pub struct DocFrequency {
     pub relative_term_frequency: f64
 }
 
-#[derive(Eq, Clone)]
+#[derive(PartialEq, Eq, Clone)]
 pub struct PostRecord {
     pub doc_id: usize,
     pub weight: usize
 }
 
+// Higher weights rank first, and equal weights fall back to the lower doc id so the order never depends on collection order
 impl Ord for PostRecord {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.weight.cmp(&other.weight)
+        self.weight.cmp(&other.weight).then_with(|| other.doc_id.cmp(&self.doc_id))
     }
 }
 
 impl PartialOrd for PostRecord {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.weight.cmp(&other.weight))
+        Some(self.cmp(other))
     }
 }
 
-impl PartialEq for PostRecord {
-    fn eq(&self, other: &Self) -> bool {
-        self.weight == other.weight
-    }
+#[derive(Clone)]
+pub struct DictRecord {
+    pub term: String,
+    pub num_docs: usize,
+    pub post_line_start: usize,
+    // The highest weight in the term's postings, missing in indexes written before it was recorded
+    pub max_weight: Option<usize>
 }
 
-pub struct DictRecord {
+// A distinct token from the query, with the factor its posting weights are multiplied by
+#[derive(Clone)]
+pub struct QueryTerm {
+    pub token: String,
+    pub boost: f64,
+    // Documents without a required term are never matched
+    pub required: bool
+}
+
+pub struct Suggestion {
     pub term: String,
     pub num_docs: usize,
-    pub post_line_start: usize
+    pub popularity: usize
 }
 
+#[derive(Clone)]
 pub struct MapRecord {
     pub doc_id: usize,
-    pub file_name: String
+    pub file_name: String,
+    pub size: u64,
+    // Seconds since the unix epoch
+    pub modified: u64,
+    pub content_type: String,
+    pub encoding: String,
+    pub title: Option<String>,
+    // Number of indexed tokens, which term frequencies were normalized by
+    pub num_tokens: usize,
+    pub status: DocStatus
+}
+
+impl MapRecord {
+    pub fn new(doc_id: usize, file_name: String, size: u64, modified: u64) -> Self {
+        Self { doc_id, file_name, size, modified, content_type: String::new(), encoding: String::new(), title: None, num_tokens: 0, status: DocStatus::Indexed }
+    }
+}
+
+// Documents the tokenizer couldn't read are marked failed with the error, and left out of the index when it's written
+#[derive(Clone, PartialEq, Debug)]
+pub enum DocStatus {
+    Indexed,
+    Failed(String)
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct IndexMetadata {
+    #[serde(default)]
+    pub indir: Option<String>,
+    #[serde(default)]
+    pub doc_store: bool,
+    #[serde(default)]
+    pub postings_order: PostingsOrder,
+    // Milliseconds since the unix epoch when the index was built, so readers can tell when it has been rebuilt
+    #[serde(default)]
+    pub generation: u64,
+    // The most dict lines any lookup has to check to find a term, so lookups for missing terms can stop there.
+    // The dict is never more than MAX_LOAD_FACTOR full, which keeps this short
+    #[serde(default)]
+    pub max_dict_probes: usize,
+    #[serde(default)]
+    pub dict_load_factor: f64,
+    #[serde(default)]
+    pub dict_hash_function: HashFunction,
+    // Indexes without one were pruned with the default policy
+    #[serde(default)]
+    pub pruning: PruningPolicy
+}
+
+// The order of each term's postings in the post file. Older indexes wrote them in the order documents were tokenized.
+// Impact ordered postings go from highest to lowest weight, which speeds up ranked queries but means lists can't be intersected
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PostingsOrder {
+    #[default]
+    Unsorted,
+    DocId,
+    Impact
+}
+
+// Which terms are left out of the index. Terms are kept if they're in at least min_docs documents and appear at least
+// min_occurrences times across them, or with keep_numbers, if they contain a digit, such as ticket numbers and error codes.
+// The default leaves out terms that appear once in one document
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct PruningPolicy {
+    pub min_docs: usize,
+    pub min_occurrences: usize,
+    pub keep_numbers: bool
+}
+
+impl Default for PruningPolicy {
+    fn default() -> Self {
+        Self { min_docs: 1, min_occurrences: 2, keep_numbers: false }
+    }
+}
+
+impl PruningPolicy {
+    pub fn keep_all() -> Self {
+        Self { min_docs: 1, min_occurrences: 1, keep_numbers: false }
+    }
+
+    pub fn keeps(&self, term: &str, bucket: &GlobHTBucket) -> bool {
+        (self.keep_numbers && term.bytes().any(|byte| byte.is_ascii_digit()))
+            || (bucket.get_num_docs() >= self.min_docs && bucket.get_total_frequency() >= self.min_occurrences)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
+pub struct StoredDoc {
+    pub title: Option<String>,
+    pub url: String,
+    pub text: String
+}
+
+// A summary of one run of the tokenizer, written next to the index so pipelines can check it, such as for a drop in documents
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct IndexStats {
+    pub generation: u64,
+    pub docs_total: usize,
+    pub docs_indexed: usize,
+    pub docs_failed: usize,
+    pub tokens: usize,
+    pub unique_terms: usize,
+    // Terms left out of the index by its pruning policy
+    pub rare_terms_pruned: usize,
+    pub runs: usize,
+    pub tokenize_seconds: f64,
+    pub total_seconds: f64,
+    pub docs_per_second: f64,
+    // Bytes in each of the index's files
+    pub file_sizes: BTreeMap<String, u64>,
+    // The error for each document that couldn't be indexed, by file name
+    pub failures: BTreeMap<String, String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct FileSizes {
     pub num_dict_lines: usize,
     pub post_line_start_length: usize,
     pub num_docs_length: usize,
     pub doc_id_length: usize,
     pub weight_length: usize,
-    pub map_name_length: usize
+    pub map_name_length: usize,
+    #[serde(default)]
+    pub num_term_lines: usize,
+    #[serde(default)]
+    pub store_offset_length: usize,
+    // Indexes without these only have the file name in the map
+    #[serde(default)]
+    pub map_size_length: usize,
+    #[serde(default)]
+    pub map_modified_length: usize,
+    #[serde(default)]
+    pub map_content_type_length: usize,
+    #[serde(default)]
+    pub map_encoding_length: usize,
+    #[serde(default)]
+    pub map_title_length: usize,
+    #[serde(default)]
+    pub map_tokens_length: usize,
+    #[serde(default)]
+    pub max_weight_length: usize
 }
 
 impl FileSizes {
-    pub fn new(glob_ht: &HashTable<GlobHTBucket>, map_files: &Vec<MapRecord>) -> Self {
+    pub fn new(glob_ht: &HashTable<GlobHTBucket>, map_files: &[MapRecord], pruning: &PruningPolicy) -> Self {
         Self {
             num_dict_lines: glob_ht.get_size(),
-            post_line_start_length: Self::calculate_post_line_start_length(&glob_ht),
-            num_docs_length: Self::calculate_num_docs_length(&glob_ht),
+            post_line_start_length: Self::calculate_post_line_start_length(glob_ht),
+            num_docs_length: Self::calculate_num_docs_length(glob_ht),
+            num_term_lines: Self::calculate_num_term_lines(glob_ht, pruning),
+            ..Self::from_map_files(map_files)
+        }
+    }
+
+    // The sizes that only depend on the documents, leaving the dict's sizes to be filled in once its terms are known
+    pub fn from_map_files(map_files: &[MapRecord]) -> Self {
+        Self {
+            num_dict_lines: 0,
+            post_line_start_length: 0,
+            num_docs_length: 0,
             doc_id_length: map_files.len().to_string().len(),
-            weight_length: Self::calculate_weight_length(&map_files),
-            map_name_length: Self::calculate_map_name_length(&map_files)
+            weight_length: Self::calculate_weight_length(map_files),
+            map_name_length: Self::calculate_map_name_length(map_files),
+            num_term_lines: 0,
+            store_offset_length: 0,
+            map_size_length: Self::calculate_map_field_length(map_files, |record| record.size.to_string().len()),
+            map_modified_length: Self::calculate_map_field_length(map_files, |record| record.modified.to_string().len()),
+            map_content_type_length: Self::calculate_map_field_length(map_files, |record| record.content_type.len()),
+            map_encoding_length: Self::calculate_map_field_length(map_files, |record| record.encoding.len()),
+            map_title_length: Self::calculate_map_field_length(map_files, |record| record.title.as_ref().map_or(0, |title| title.len())).min(MAX_TITLE_LENGTH),
+            map_tokens_length: Self::calculate_map_field_length(map_files, |record| record.num_tokens.to_string().len()),
+            max_weight_length: Self::calculate_weight_length(map_files)
         }
     }
 
     pub fn get_dict_record_size(&self) -> usize {
-        TERM_LENGTH + self.num_docs_length + self.post_line_start_length + 3
+        let max_weight_length = if self.has_max_weights() { self.max_weight_length + 1 } else { 0 };
+        TERM_LENGTH + self.num_docs_length + self.post_line_start_length + 3 + max_weight_length
     }
 
     pub fn get_post_record_size(&self) -> usize {
         self.doc_id_length + self.weight_length + 2
     }
 
+    pub fn get_skip_record_size(&self) -> usize {
+        self.doc_id_length + 1
+    }
+
+    pub fn get_term_record_size(&self) -> usize {
+        TERM_LENGTH + self.num_docs_length + 2
+    }
+
+    pub fn get_store_index_record_size(&self) -> usize {
+        2 * self.store_offset_length + 2
+    }
+
     pub fn get_map_record_size(&self) -> usize {
-        self.map_name_length + 1
+        if !self.has_map_metadata() { return self.map_name_length + 1 }
+        let tokens_length = if self.has_map_tokens() { self.map_tokens_length + 1 } else { 0 };
+        self.map_name_length + self.map_size_length + self.map_modified_length + self.map_content_type_length
+            + self.map_encoding_length + self.map_title_length + 6 + tokens_length
+    }
+
+    pub fn has_max_weights(&self) -> bool {
+        self.max_weight_length > 0
+    }
+
+    pub fn has_map_tokens(&self) -> bool {
+        self.map_tokens_length > 0
+    }
+
+    pub fn has_map_metadata(&self) -> bool {
+        self.map_size_length > 0
     }
 
     fn calculate_num_docs_length(glob_ht: &HashTable<GlobHTBucket>) -> usize {
-        let num_docs_max = glob_ht.get_buckets().iter().fold(0, |max, bucket| cmp::max(max, match bucket {
-            Some(entry) => entry.value.get_num_docs(),
-            None => 0 
-        }));
+        let num_docs_max = glob_ht.iter().fold(0, |max, entry| cmp::max(max, entry.value.get_num_docs()));
         num_docs_max.to_string().len()
     }
 
     fn calculate_post_line_start_length(glob_ht: &HashTable<GlobHTBucket>) -> usize {
-        let num_post_records = glob_ht.get_buckets().iter().fold(0, |sum, bucket| sum + match bucket {
-            Some(entry) => entry.value.get_files().len(),
-            None => 0
-        });
+        let num_post_records = glob_ht.iter().fold(0, |sum, entry| sum + entry.value.get_files().len());
         num_post_records.to_string().len()
     }
 
-    fn calculate_weight_length(map_files: &Vec<MapRecord>) -> usize {
+    fn calculate_num_term_lines(glob_ht: &HashTable<GlobHTBucket>, pruning: &PruningPolicy) -> usize {
+        glob_ht.iter().filter(|entry| pruning.keeps(&entry.key, &entry.value)).count()
+    }
+
+    fn calculate_weight_length(map_files: &[MapRecord]) -> usize {
         let max_idf = 1.0 + (map_files.len() as f64).log10();
         let max_weight = (max_idf * WEIGHT_MULTIPLIER) as usize;
         max_weight.to_string().len()
     }
 
-    fn calculate_map_name_length(map_files: &Vec<MapRecord>) -> usize {
-        map_files.iter().max_by_key(|record| record.file_name.len()).unwrap().file_name.len()
+    fn calculate_map_name_length(map_files: &[MapRecord]) -> usize {
+        map_files.iter().map(|record| record.file_name.len()).max().unwrap_or(0)
+    }
+
+    fn calculate_map_field_length(map_files: &[MapRecord], field_length: fn(&MapRecord) -> usize) -> usize {
+        map_files.iter().map(field_length).max().unwrap_or(0)
     }
 }
 
@@ -121,6 +460,10 @@ impl GlobHTBucket {
         Self { files: vec![DocFrequency { doc_id, raw_term_frequency, relative_term_frequency }] }
     }
 
+    pub fn from_files(files: Vec<DocFrequency>) -> Self {
+        Self { files }
+    }
+
     pub fn get_num_docs(&self) -> usize {
         self.files.len()
     }
@@ -132,10 +475,6 @@ impl GlobHTBucket {
     pub fn get_files(&self) -> &Vec<DocFrequency> {
         &self.files
     }
-
-    pub fn is_rare(&self) -> bool {
-        self.get_num_docs() <= 1 && self.get_total_frequency() <= 1
-    }
 }
 
 impl AddAssign for GlobHTBucket {
@@ -0,0 +1,45 @@
+const SECONDS_PER_DAY: i64 = 86400;
+
+// Days since the unix epoch for a date in the proleptic gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn to_timestamp(days: i64) -> u64 {
+    (days * SECONDS_PER_DAY).max(0) as u64
+}
+
+// Parses "YYYY", "YYYY-MM" or "YYYY-MM-DD" into the first and last second of that period
+pub fn parse_period(date: &str) -> Option<(u64, u64)> {
+    let parts: Vec<i64> = date.split('-').map(|part| part.parse().ok()).collect::<Option<Vec<i64>>>()?;
+    let (start, end) = match parts[..] {
+        [year] => (days_from_civil(year, 1, 1), days_from_civil(year + 1, 1, 1)),
+        [year, month] if (1..=12).contains(&month) => {
+            let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+            (days_from_civil(year, month, 1), days_from_civil(next_year, next_month, 1))
+        }
+        [year, month, day] if (1..=12).contains(&month) && (1..=31).contains(&day) => {
+            let start = days_from_civil(year, month, day);
+            (start, start + 1)
+        }
+        _ => return None
+    };
+    Some((to_timestamp(start), to_timestamp(end).saturating_sub(1)))
+}
+
+// Inverse of days_from_civil, only the year is needed so month and day are discarded
+pub fn year_from_timestamp(timestamp: u64) -> i64 {
+    let days = timestamp as i64 / SECONDS_PER_DAY + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let year = year_of_era + era * 400;
+    if month_index >= 10 { year + 1 } else { year }
+}
@@ -0,0 +1,146 @@
+use std::{fs::{self, File}, io::Read, path::Path, str};
+
+use encoding::{all::ISO_8859_1, Encoding, DecoderTrap};
+use regex::Regex;
+use lazy_static::lazy_static;
+
+use crate::error::Error;
+use crate::constants::STREAM_CHUNK_BYTES;
+
+lazy_static! {
+    static ref RE_TITLE: Regex = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+    static ref RE_CLEAN_WHITESPACE: Regex = Regex::new(r"\s+").unwrap();
+}
+
+pub struct Document {
+    pub contents: String,
+    pub encoding: &'static str
+}
+
+pub fn read_latin1_file(file_path: &str) -> Result<String, Error> {
+    let bytes = fs::read(file_path)?;
+    Ok(decode_latin1(&bytes))
+}
+
+fn decode_latin1(bytes: &[u8]) -> String {
+    match ISO_8859_1.decode(bytes, DecoderTrap::Ignore) {
+        Ok(string) => string,
+        Err(str) => str.into_owned()
+    }
+}
+
+// Files that are valid utf-8 are read as such, anything else falls back to latin-1
+pub fn read_document(file_path: &str) -> Result<Document, Error> {
+    let bytes = fs::read(file_path)?;
+    match String::from_utf8(bytes) {
+        Ok(contents) if contents.is_ascii() => Ok(Document { contents, encoding: "ascii" }),
+        Ok(contents) => Ok(Document { contents, encoding: "utf-8" }),
+        Err(e) => Ok(Document { contents: decode_latin1(e.as_bytes()), encoding: "latin-1" })
+    }
+}
+
+// Reads only the first max_bytes of a document, for when the start of it is enough. The encoding is chosen from those bytes
+// alone, and a character cut off at the end is dropped
+pub fn read_document_head(file_path: &str, max_bytes: usize) -> Result<String, Error> {
+    let mut bytes = vec![];
+    File::open(file_path)?.take(max_bytes as u64).read_to_end(&mut bytes)?;
+    let valid_length = match str::from_utf8(&bytes) {
+        Ok(_) => bytes.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => return Ok(decode_latin1(&bytes))
+    };
+    Ok(String::from_utf8_lossy(&bytes[..valid_length]).into_owned())
+}
+
+// The longest start of the text that fits in max_bytes without splitting a character
+pub fn truncate_text(text: &str, max_bytes: usize) -> &str {
+    let mut end = max_bytes.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+// Reads up to STREAM_CHUNK_BYTES at a time, or None once the file has been read
+fn read_chunk(file: &mut File) -> Result<Option<Vec<u8>>, Error> {
+    let mut chunk = vec![];
+    file.by_ref().take(STREAM_CHUNK_BYTES as u64).read_to_end(&mut chunk)?;
+    Ok(if chunk.is_empty() { None } else { Some(chunk) })
+}
+
+// Chooses the encoding the same way read_document does, without holding more than a chunk of the file
+fn detect_encoding(file_path: &str) -> Result<&'static str, Error> {
+    let mut file = File::open(file_path)?;
+    let mut pending: Vec<u8> = vec![];
+    let mut ascii = true;
+    while let Some(chunk) = read_chunk(&mut file)? {
+        pending.extend_from_slice(&chunk);
+        // A character may be split between this chunk and the next, in which case its start is kept for the next one
+        let valid_length = match str::from_utf8(&pending) {
+            Ok(_) => pending.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return Ok("latin-1")
+        };
+        ascii &= pending[..valid_length].is_ascii();
+        pending.drain(..valid_length);
+    }
+    match (pending.is_empty(), ascii) {
+        (false, _) => Ok("latin-1"),
+        (true, true) => Ok("ascii"),
+        (true, false) => Ok("utf-8")
+    }
+}
+
+// Reads a large document a chunk at a time, passing each decoded chunk on rather than holding the whole file.
+// The file is read twice, first to choose its encoding, since a single invalid byte near the end means all of it is latin-1.
+// The returned document's contents are only the first chunk, which is what the title and content type are taken from
+pub fn stream_document(file_path: &str, mut on_chunk: impl FnMut(&str)) -> Result<Document, Error> {
+    let encoding = detect_encoding(file_path)?;
+    let mut file = File::open(file_path)?;
+    let mut pending: Vec<u8> = vec![];
+    let mut head = None;
+    while let Some(chunk) = read_chunk(&mut file)? {
+        let text = match encoding {
+            "latin-1" => decode_latin1(&chunk),
+            _ => {
+                pending.extend_from_slice(&chunk);
+                let valid_length = match str::from_utf8(&pending) {
+                    Ok(_) => pending.len(),
+                    Err(e) => e.valid_up_to()
+                };
+                let text = String::from_utf8_lossy(&pending[..valid_length]).into_owned();
+                pending.drain(..valid_length);
+                text
+            }
+        };
+        on_chunk(&text);
+        head.get_or_insert(text);
+    }
+    if !pending.is_empty() {
+        on_chunk(&String::from_utf8_lossy(&pending));
+    }
+    Ok(Document { contents: head.unwrap_or_default(), encoding })
+}
+
+pub fn extract_title(contents: &str) -> Option<String> {
+    let title = RE_TITLE.captures(contents)?.get(1)?.as_str();
+    let title = RE_CLEAN_WHITESPACE.replace_all(title, " ").trim().to_string();
+    if title.is_empty() { None } else { Some(title) }
+}
+
+// The extension decides the content type when it is a known one, otherwise the start of the document is sniffed for html
+pub fn detect_content_type(file_name: &str, contents: &str) -> &'static str {
+    let extension = Path::new(file_name).extension().and_then(|ext| ext.to_str()).unwrap_or("").to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" | "xhtml" => "text/html",
+        "xml" => "text/xml",
+        "json" => "application/json",
+        "csv" => "text/csv",
+        "md" => "text/markdown",
+        "txt" | "log" => "text/plain",
+        _ => {
+            let start: String = contents.chars().take(1024).collect::<String>().to_ascii_lowercase();
+            if start.contains("<html") || start.contains("<!doctype html") { "text/html" } else { "text/plain" }
+        }
+    }
+}
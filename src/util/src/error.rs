@@ -0,0 +1,56 @@
+use std::{fmt, io, str::FromStr};
+
+use serde_json::error::Category;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // An index file is missing fields or has values that can't be parsed
+    CorruptIndex(String),
+    // The index was written in a format this version can't read
+    IncompatibleFormat(String),
+    QuerySyntax(String)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{error}"),
+            Error::CorruptIndex(message) => write!(f, "corrupt index: {message}"),
+            Error::IncompatibleFormat(message) => write!(f, "incompatible index format: {message}"),
+            Error::QuerySyntax(message) => write!(f, "invalid query: {message}")
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+// Only used for json index files, where well formed json of the wrong shape means the index came from another version
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        match error.classify() {
+            Category::Io => Error::Io(error.into()),
+            Category::Data => Error::IncompatibleFormat(error.to_string()),
+            Category::Syntax | Category::Eof => Error::CorruptIndex(error.to_string())
+        }
+    }
+}
+
+// Parses one field of a fixed-length record, naming the file it came from if it's missing or invalid
+pub(crate) fn parse_field<T: FromStr>(field: Option<&str>, file: &str) -> Result<T, Error> {
+    field.and_then(|field| field.trim().parse().ok())
+        .ok_or_else(|| Error::CorruptIndex(format!("invalid record in {file}")))
+}
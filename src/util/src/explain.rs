@@ -0,0 +1,39 @@
+use crate::constants::*;
+use crate::data_models::{DictRecord, QueryTerm, PostRecord, NamedResult, Explanation, TermExplanation};
+use crate::hashtable::HashTable;
+
+// Boosted posting weights are relative_term_frequency * idf * WEIGHT_MULTIPLIER * boost, so the term frequency can be recovered
+// from the weight, and the raw count as well when the document's length was recorded in the map
+fn explain_term(dict_record: &DictRecord, weight: usize, boost: f64, total_docs: usize, doc_length: Option<usize>) -> TermExplanation {
+    let idf = 1.0 + (total_docs as f64 / dict_record.num_docs as f64).log10();
+    let relative_term_frequency = weight as f64 / (idf * WEIGHT_MULTIPLIER * boost);
+    TermExplanation {
+        term: dict_record.term.clone(),
+        weight,
+        raw_term_frequency: doc_length.map(|length| (relative_term_frequency * length as f64).round() as usize),
+        relative_term_frequency,
+        num_docs: dict_record.num_docs,
+        idf,
+        doc_length,
+        boost
+    }
+}
+
+// Postings are scanned once, and only those belonging to a returned result are explained
+pub fn add_explanations(named_results: &mut [NamedResult], dict_records: &[(DictRecord, QueryTerm)], term_postings: &[Vec<PostRecord>], total_docs: usize) {
+    let mut positions: HashTable<usize> = HashTable::new(named_results.len() * 3 + 1);
+    for (position, result) in named_results.iter_mut().enumerate() {
+        positions.insert_combine(&result.doc_id.to_string(), position);
+        result.explanation = Some(Explanation { terms: vec![] });
+    }
+    for ((dict_record, query_term), postings) in dict_records.iter().zip(term_postings) {
+        for posting in postings {
+            if let Some(position) = positions.get(&posting.doc_id.to_string()) {
+                let result = &mut named_results[*position];
+                let doc_length = if result.num_tokens > 0 { Some(result.num_tokens) } else { None };
+                let term = explain_term(dict_record, posting.weight, query_term.boost, total_docs, doc_length);
+                result.explanation.as_mut().unwrap().terms.push(term);
+            }
+        }
+    }
+}
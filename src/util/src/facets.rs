@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use crate::data_models::{Facets, FacetCount, MapRecord};
+use crate::dates::year_from_timestamp;
+use crate::hashtable::HashTable;
+
+fn get_extension(record: &MapRecord) -> String {
+    Path::new(&record.file_name).extension().and_then(|ext| ext.to_str()).unwrap_or("").to_ascii_lowercase()
+}
+
+// Documents directly inside indir are counted under "."
+fn get_top_level_dir(record: &MapRecord) -> String {
+    match record.file_name.split_once('/') {
+        Some((dir, _)) => dir.to_string(),
+        None => ".".to_string()
+    }
+}
+
+// A modified time of 0 means it isn't known, which is every document in indexes written before the map recorded it
+fn get_year(record: &MapRecord) -> String {
+    match record.modified {
+        0 => "unknown".to_string(),
+        modified => year_from_timestamp(modified).to_string()
+    }
+}
+
+fn sorted_counts(counts: HashTable<usize>) -> Vec<FacetCount> {
+    let mut sorted: Vec<FacetCount> = counts.iter()
+        .map(|entry| FacetCount { value: entry.key.clone(), count: entry.value })
+        .collect();
+    sorted.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    sorted
+}
+
+pub fn count_facets(doc_ids: &[usize], map_records: &[MapRecord]) -> Facets {
+    let mut extensions: HashTable<usize> = HashTable::new(64);
+    let mut directories: HashTable<usize> = HashTable::new(64);
+    let mut years: HashTable<usize> = HashTable::new(64);
+    for doc_id in doc_ids {
+        let record = &map_records[*doc_id];
+        extensions.insert_combine(&get_extension(record), 1);
+        directories.insert_combine(&get_top_level_dir(record), 1);
+        years.insert_combine(&get_year(record), 1);
+    }
+    Facets { extensions: sorted_counts(extensions), directories: sorted_counts(directories), years: sorted_counts(years) }
+}
@@ -1,117 +1,576 @@
-use std::{num::ParseIntError, ops::AddAssign, mem::take};
+use std::{ops::AddAssign, mem::take, iter::FilterMap, slice, vec};
 
+use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
-use hex;
 
-pub fn hash_function(key: &str, size: &usize) -> Result<usize, ParseIntError> {
-    let mut hasher = Sha256::new();
-    hasher.update(key.as_bytes());
-    let hash_bytes = hasher.finalize();
-    let hash_str = hex::encode(hash_bytes);
-    let hash_slice = &hash_str[hash_str.len() - 7..];
-    let result = usize::from_str_radix(hash_slice, 16)?;
-    Ok(result % size)
+use crate::constants::{MAX_LOAD_FACTOR, FX_HASH_SEED};
+
+// The hash used to place keys in a table. The dict is read back with the hash that built it, so both are kept stable.
+// Indexes that don't record one were built with Sha256
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HashFunction {
+    #[default]
+    Sha256,
+    Fx
+}
+
+impl HashFunction {
+    pub fn hash(&self, key: &str, size: usize) -> usize {
+        match self {
+            HashFunction::Sha256 => sha256_hash(key) % size,
+            // Fx mixes the high bits best, so they pick the slot rather than the remainder
+            HashFunction::Fx => ((fx_hash(key) as u128 * size as u128) >> 64) as usize
+        }
+    }
+}
+
+// The last 7 hex digits of the key's sha256, as the python program used
+fn sha256_hash(key: &str) -> usize {
+    let hash_bytes = Sha256::digest(key.as_bytes());
+    let last_bytes = [hash_bytes[28], hash_bytes[29], hash_bytes[30], hash_bytes[31]];
+    (u32::from_be_bytes(last_bytes) & 0x0fff_ffff) as usize
+}
+
+// Picks which of a sharded table's shards a key goes in. The bytes are swapped so this uses different bits of the
+// hash than the slot within the shard does, otherwise each shard's keys would all hash to the same part of it
+pub fn shard_index(key: &str, num_shards: usize) -> usize {
+    ((fx_hash(key).swap_bytes() as u128 * num_shards as u128) >> 64) as usize
+}
+
+// Rustc's fx hash over 8 byte words, written out here so a dependency update can't change where dict terms are
+fn fx_hash(key: &str) -> u64 {
+    let add_word = |hash: u64, word: u64| (hash.rotate_left(5) ^ word).wrapping_mul(FX_HASH_SEED);
+    let mut words = key.as_bytes().chunks_exact(8);
+    let mut hash = (&mut words).fold(0, |hash, word| add_word(hash, u64::from_le_bytes(word.try_into().unwrap())));
+    let mut last_word = [0; 8];
+    last_word[..words.remainder().len()].copy_from_slice(words.remainder());
+    hash = add_word(hash, u64::from_le_bytes(last_word));
+    add_word(hash, key.len() as u64)
 }
 
 pub fn rehash(hash: &usize, size: &usize) -> usize {
     (hash + 3) % size
 }
 
+// Probing steps by 3, so it only reaches every slot when the size isn't a multiple of 3.
+// Sizes are rounded up to the next one that isn't, and are never 0
+pub fn probe_size(size: usize) -> usize {
+    let size = size.max(1);
+    if size.is_multiple_of(3) { size + 1 } else { size }
+}
+
 #[derive(Clone)]
 pub struct TableEntry<T> {
     pub key: String,
     pub value: T
 }
 
+// Removed entries leave a tombstone so probes for keys further along the chain keep going past them.
+// The dict writes them the same way as rare terms, as !DELETED lines
+#[derive(Clone)]
+pub enum Slot<T> {
+    Empty,
+    Deleted,
+    Occupied(TableEntry<T>)
+}
+
+impl<T> Slot<T> {
+    fn entry(&self) -> Option<&TableEntry<T>> {
+        match self {
+            Slot::Occupied(entry) => Some(entry),
+            _ => None
+        }
+    }
+
+    fn into_entry(self) -> Option<TableEntry<T>> {
+        match self {
+            Slot::Occupied(entry) => Some(entry),
+            _ => None
+        }
+    }
+}
+
 pub struct HashTable<T> {
-    buckets: Vec<Option<TableEntry<T>>>,
-    num_elements: usize
+    slots: Vec<Slot<T>>,
+    num_elements: usize,
+    num_deleted: usize,
+    hash_function: HashFunction
 }
 
-impl<T> HashTable<T>
-where T: Clone + AddAssign
-{
+fn empty_slots<T>(size: usize) -> Vec<Slot<T>> {
+    (0..size).map(|_| Slot::Empty).collect()
+}
+
+impl<T> HashTable<T> {
     pub fn new(size: usize) -> Self {
-        Self { 
-            buckets: vec![None; size],
-            num_elements: 0
+        Self::with_hash_function(size, HashFunction::Fx)
+    }
+
+    pub fn with_hash_function(size: usize, hash_function: HashFunction) -> Self {
+        Self {
+            slots: empty_slots(probe_size(size)),
+            num_elements: 0,
+            num_deleted: 0,
+            hash_function
         }
     }
 
-    fn reindex(&mut self) {
-        if self.num_elements * 2 > self.buckets.len() {
-            let old_buckets = take(&mut self.buckets);
-            self.buckets = vec![None; old_buckets.len() * 2];
-            for bucket in old_buckets {
-                if let Some(entry) = bucket {
-                    self.reinsert_helper(entry);
-                }
+    // Ok with the slot holding the key, or else Err with the slot it would be inserted into,
+    // which is the first tombstone passed on the way to an empty slot
+    fn find_slot(&self, key: &str) -> Result<usize, usize> {
+        let size = self.get_size();
+        let mut hash = self.hash_function.hash(key, size);
+        let mut first_deleted = None;
+        for _ in 0..size {
+            match &self.slots[hash] {
+                Slot::Occupied(entry) if entry.key == key => return Ok(hash),
+                Slot::Occupied(_) => (),
+                Slot::Deleted => { first_deleted.get_or_insert(hash); }
+                Slot::Empty => return Err(first_deleted.unwrap_or(hash))
             }
+            hash = rehash(&hash, &size);
         }
+        Err(first_deleted.unwrap_or(hash))
     }
 
-    fn reinsert_helper(&mut self, entry: TableEntry<T>) {
-        if let Ok(mut hash) = hash_function(&entry.key, &self.get_size()) {
-            while let Some(Some(bucket)) = self.buckets.get(hash) {
-                if bucket.key != entry.key { hash = rehash(&hash, &self.get_size())}
-                else { break }
+    // How many slots a lookup for the key checks before reaching it, if it's in the table
+    pub fn probe_length(&self, key: &str) -> Option<usize> {
+        let size = self.get_size();
+        let mut hash = self.hash_function.hash(key, size);
+        for probes in 1..=size {
+            match &self.slots[hash] {
+                Slot::Occupied(entry) if entry.key == key => return Some(probes),
+                Slot::Empty => return None,
+                _ => hash = rehash(&hash, &size)
             }
-            if let Some(None) = self.buckets.get_mut(hash) {
-                self.buckets[hash] = Some(entry);
+        }
+        None
+    }
+
+    // Grows before an insert could take the table over MAX_LOAD_FACTOR, so there is always an empty slot to end a probe.
+    // Tombstones count towards the load, and are dropped by rebuilding at the same size when they make up most of it
+    fn reindex(&mut self) {
+        let max_load = self.slots.len() as f64 * MAX_LOAD_FACTOR;
+        if (self.num_elements + self.num_deleted + 1) as f64 > max_load {
+            let new_size = if (self.num_elements + 1) as f64 > max_load / 2.0 { self.slots.len() * 2 } else { self.slots.len() };
+            let old_slots = take(&mut self.slots);
+            self.slots = empty_slots(new_size);
+            self.num_deleted = 0;
+            for entry in old_slots.into_iter().filter_map(Slot::into_entry) {
+                if let Err(slot) = self.find_slot(&entry.key) {
+                    self.slots[slot] = Slot::Occupied(entry);
+                }
             }
         }
     }
 
+    // Empties the table while keeping its size
     pub fn reset(&mut self) {
-        for bucket in &mut self.buckets {
-            *bucket = None;
+        for slot in &mut self.slots {
+            *slot = Slot::Empty;
         }
+        self.num_elements = 0;
+        self.num_deleted = 0;
     }
 
-    pub fn insert_combine(&mut self, key: &str, value: T) {
+    pub fn entry(&mut self, key: &str) -> Entry<'_, T> {
         self.reindex();
-        if let Ok(mut hash) = hash_function(key, &self.get_size()) {
-            while let Some(Some(bucket)) = self.buckets.get(hash) {
-                if bucket.key != key { hash = rehash(&hash, &self.get_size())}
-                else { break }
-            }
-            match self.buckets.get_mut(hash) {
-                Some(Some(bucket)) =>  { 
-                    bucket.value += value;
-                }
-                Some(None) =>  { 
-                    self.buckets[hash] = Some(TableEntry { key: key.to_string(), value: value });
-                    self.num_elements += 1;
-                }
-                _ => ()
+        match self.find_slot(key) {
+            Ok(slot) => Entry::Occupied(OccupiedEntry { table: self, slot }),
+            Err(slot) => Entry::Vacant(VacantEntry { table: self, slot, key: key.to_string() })
+        }
+    }
+
+    // Replaces the key's value, returning the old one
+    pub fn insert(&mut self, key: &str, value: T) -> Option<T> {
+        match self.entry(key) {
+            Entry::Occupied(mut entry) => Some(entry.insert(value)),
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                None
             }
         }
     }
 
+    pub fn remove(&mut self, key: &str) -> Option<T> {
+        let slot = self.find_slot(key).ok()?;
+        Some(OccupiedEntry { table: self, slot }.remove())
+    }
+
     pub fn get(&self, key: &str) -> Option<&T> {
-        if let Ok(mut hash) = hash_function(key, &self.get_size()) {
-            while let Some(Some(bucket)) = self.buckets.get(hash) {
-                if bucket.key == key { return Some(&bucket.value) }
-                else { hash = rehash(&hash, &self.get_size()) }
-            }
+        let slot = self.find_slot(key).ok()?;
+        self.slots[slot].entry().map(|entry| &entry.value)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut T> {
+        let slot = self.find_slot(key).ok()?;
+        match &mut self.slots[slot] {
+            Slot::Occupied(entry) => Some(&mut entry.value),
+            _ => None
         }
-        None
     }
 
     pub fn intable(&self, key: &str) -> bool {
         self.get(key).is_some()
     }
 
-    pub fn get_buckets(&self) -> &Vec<Option<TableEntry<T>>> {
-        &self.buckets
+    pub fn iter(&self) -> Iter<'_, T> {
+        self.slots.iter().filter_map(Slot::entry)
     }
 
+    // Every slot in order, including empty ones and tombstones, for writing the table out as the dict
+    pub fn get_slots(&self) -> &[Slot<T>] {
+        &self.slots
+    }
+
+    // The slots as they were before tombstones, with removed entries showing up as empty. The table no longer stores
+    // Option<TableEntry> slots to borrow, so this returns a new Vec of references instead of &Vec<Option<TableEntry<T>>>
+    #[deprecated(note = "use get_slots, which tells tombstones apart from empty slots. This now returns Vec<Option<&TableEntry<T>>> rather than &Vec<Option<TableEntry<T>>>")]
+    pub fn get_buckets(&self) -> Vec<Option<&TableEntry<T>>> {
+        self.get_slots().iter().map(Slot::entry).collect()
+    }
+
+    // The number of slots, which is what keys are hashed over
     pub fn get_size(&self) -> usize {
-        self.buckets.len()
+        self.slots.len()
     }
 
-    pub fn get_num_elements(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.num_elements
     }
 
-}
\ No newline at end of file
+    #[deprecated(note = "use len")]
+    pub fn get_num_elements(&self) -> usize {
+        self.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_elements == 0
+    }
+
+    // How many entries the table holds before it grows
+    pub fn capacity(&self) -> usize {
+        (self.slots.len() as f64 * MAX_LOAD_FACTOR) as usize
+    }
+
+    pub fn get_hash_function(&self) -> HashFunction {
+        self.hash_function
+    }
+}
+
+impl<T> HashTable<T>
+where T: AddAssign
+{
+    pub fn insert_combine(&mut self, key: &str, value: T) {
+        match self.entry(key) {
+            Entry::Occupied(mut entry) => *entry.get_mut() += value,
+            Entry::Vacant(entry) => { entry.insert(value); }
+        }
+    }
+}
+
+pub enum Entry<'a, T> {
+    Occupied(OccupiedEntry<'a, T>),
+    Vacant(VacantEntry<'a, T>)
+}
+
+pub struct OccupiedEntry<'a, T> {
+    table: &'a mut HashTable<T>,
+    slot: usize
+}
+
+pub struct VacantEntry<'a, T> {
+    table: &'a mut HashTable<T>,
+    slot: usize,
+    key: String
+}
+
+impl<'a, T> Entry<'a, T> {
+    pub fn key(&self) -> &str {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key()
+        }
+    }
+
+    pub fn or_insert(self, default: T) -> &'a mut T {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> T>(self, default: F) -> &'a mut T {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default())
+        }
+    }
+
+    pub fn and_modify<F: FnOnce(&mut T)>(mut self, modify: F) -> Self {
+        if let Entry::Occupied(entry) = &mut self {
+            modify(entry.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, T: Default> Entry<'a, T> {
+    pub fn or_default(self) -> &'a mut T {
+        self.or_insert_with(T::default)
+    }
+}
+
+impl<'a, T> OccupiedEntry<'a, T> {
+    fn table_entry(&self) -> &TableEntry<T> {
+        match &self.table.slots[self.slot] {
+            Slot::Occupied(entry) => entry,
+            _ => unreachable!("occupied entries always point at an occupied slot")
+        }
+    }
+
+    fn into_table_entry(self) -> &'a mut TableEntry<T> {
+        match &mut self.table.slots[self.slot] {
+            Slot::Occupied(entry) => entry,
+            _ => unreachable!("occupied entries always point at an occupied slot")
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.table_entry().key
+    }
+
+    pub fn get(&self) -> &T {
+        &self.table_entry().value
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        match &mut self.table.slots[self.slot] {
+            Slot::Occupied(entry) => &mut entry.value,
+            _ => unreachable!("occupied entries always point at an occupied slot")
+        }
+    }
+
+    pub fn into_mut(self) -> &'a mut T {
+        &mut self.into_table_entry().value
+    }
+
+    pub fn insert(&mut self, value: T) -> T {
+        std::mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> T {
+        let slot = std::mem::replace(&mut self.table.slots[self.slot], Slot::Deleted);
+        self.table.num_elements -= 1;
+        self.table.num_deleted += 1;
+        match slot {
+            Slot::Occupied(entry) => entry.value,
+            _ => unreachable!("occupied entries always point at an occupied slot")
+        }
+    }
+}
+
+impl<'a, T> VacantEntry<'a, T> {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn insert(self, value: T) -> &'a mut T {
+        if let Slot::Deleted = self.table.slots[self.slot] {
+            self.table.num_deleted -= 1;
+        }
+        self.table.num_elements += 1;
+        self.table.slots[self.slot] = Slot::Occupied(TableEntry { key: self.key, value });
+        match &mut self.table.slots[self.slot] {
+            Slot::Occupied(entry) => &mut entry.value,
+            _ => unreachable!("the slot was just filled")
+        }
+    }
+}
+
+pub type Iter<'a, T> = FilterMap<slice::Iter<'a, Slot<T>>, fn(&Slot<T>) -> Option<&TableEntry<T>>>;
+pub type IntoIter<T> = FilterMap<vec::IntoIter<Slot<T>>, fn(Slot<T>) -> Option<TableEntry<T>>>;
+
+impl<'a, T> IntoIterator for &'a HashTable<T> {
+    type Item = &'a TableEntry<T>;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T> IntoIterator for HashTable<T> {
+    type Item = TableEntry<T>;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.slots.into_iter().filter_map(Slot::into_entry)
+    }
+}
+
+// Later values for a key replace earlier ones, as with insert
+impl<K: AsRef<str>, T> Extend<(K, T)> for HashTable<T> {
+    fn extend<I: IntoIterator<Item = (K, T)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key.as_ref(), value);
+        }
+    }
+}
+
+impl<K: AsRef<str>, T> FromIterator<(K, T)> for HashTable<T> {
+    fn from_iter<I: IntoIterator<Item = (K, T)>>(iter: I) -> Self {
+        let iter = iter.into_iter();
+        let mut table = HashTable::new((iter.size_hint().0 as f64 / MAX_LOAD_FACTOR) as usize + 1);
+        table.extend(iter);
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keys that hash to the same slot, so the second is only reached by probing past the first
+    fn colliding_keys(size: usize) -> (String, String) {
+        let keys: Vec<String> = (0..1000).map(|key| format!("key{key}")).collect();
+        let first = &keys[0];
+        let slot = HashFunction::Fx.hash(first, size);
+        let second = keys[1..].iter().find(|key| HashFunction::Fx.hash(key, size) == slot).unwrap();
+        (first.clone(), second.clone())
+    }
+
+    fn count_deleted<T>(table: &HashTable<T>) -> usize {
+        table.get_slots().iter().filter(|slot| matches!(slot, Slot::Deleted)).count()
+    }
+
+    #[test]
+    fn lookups_probe_past_tombstones() {
+        let mut table = HashTable::new(16);
+        let (first, second) = colliding_keys(table.get_size());
+        table.insert(&first, 1);
+        table.insert(&second, 2);
+        assert_eq!(table.remove(&first), Some(1));
+        assert_eq!(count_deleted(&table), 1);
+        assert_eq!(table.get(&first), None);
+        assert_eq!(table.get(&second), Some(&2));
+        assert_eq!(table.probe_length(&second), Some(2));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn inserts_reuse_tombstones() {
+        let mut table = HashTable::new(16);
+        let (first, second) = colliding_keys(table.get_size());
+        table.insert(&first, 1);
+        table.insert(&second, 2);
+        table.remove(&first);
+        assert_eq!(table.insert(&second, 3), Some(2));
+        assert_eq!(count_deleted(&table), 1);
+        table.insert(&first, 4);
+        assert_eq!(count_deleted(&table), 0);
+        assert_eq!(table.get(&first), Some(&4));
+        assert_eq!(table.get(&second), Some(&3));
+        assert_eq!(table.remove("missing"), None);
+    }
+
+    #[test]
+    fn reindex_grows_and_keeps_every_entry() {
+        let mut table = HashTable::new(4);
+        let initial_size = table.get_size();
+        for key in 0..1000 {
+            table.insert(&key.to_string(), key);
+        }
+        assert_eq!(table.len(), 1000);
+        assert!(table.get_size() > initial_size);
+        assert!(table.len() <= table.capacity());
+        assert!((0..1000).all(|key| table.get(&key.to_string()) == Some(&key)));
+    }
+
+    #[test]
+    fn reindex_drops_tombstones_without_growing() {
+        let mut table = HashTable::new(64);
+        let size = table.get_size();
+        for key in 0..1000 {
+            table.insert(&key.to_string(), key);
+            table.remove(&key.to_string());
+        }
+        assert_eq!(table.get_size(), size);
+        assert!(count_deleted(&table) <= table.capacity());
+        assert!(table.is_empty());
+        table.insert("kept", 1);
+        assert_eq!(table.get("kept"), Some(&1));
+    }
+
+    #[test]
+    fn entry_inserts_modifies_and_removes() {
+        let mut table: HashTable<usize> = HashTable::new(8);
+        *table.entry("a").or_insert(1) += 10;
+        assert_eq!(table.get("a"), Some(&11));
+        table.entry("a").and_modify(|value| *value *= 2).or_insert(0);
+        table.entry("b").and_modify(|value| *value *= 2).or_insert(5);
+        assert_eq!(table.get("a"), Some(&22));
+        assert_eq!(table.get("b"), Some(&5));
+        *table.entry("c").or_default() += 1;
+        assert_eq!(table.get("c"), Some(&1));
+        assert_eq!(table.entry("d").key(), "d");
+        match table.entry("a") {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.key(), "a");
+                assert_eq!(entry.insert(7), 22);
+                assert_eq!(entry.remove(), 7);
+            }
+            Entry::Vacant(_) => panic!("a should be in the table")
+        }
+        assert_eq!(table.get("a"), None);
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn extend_replaces_earlier_values() {
+        let mut table: HashTable<usize> = [("a", 1), ("b", 2)].into_iter().collect();
+        table.extend([("b".to_string(), 3), ("c".to_string(), 4)]);
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.get("a"), Some(&1));
+        assert_eq!(table.get("b"), Some(&3));
+        assert_eq!(table.get("c"), Some(&4));
+        let mut keys: Vec<&str> = table.iter().map(|entry| entry.key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn reset_empties_and_keeps_size() {
+        let mut table = HashTable::new(16);
+        for key in 0..20 {
+            table.insert(&key.to_string(), key);
+        }
+        table.remove("0");
+        let size = table.get_size();
+        table.reset();
+        assert_eq!(table.get_size(), size);
+        assert!(table.is_empty());
+        assert_eq!(count_deleted(&table), 0);
+        assert_eq!(table.get("1"), None);
+        table.insert("1", 1);
+        assert_eq!(table.get("1"), Some(&1));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn get_buckets_shows_tombstones_as_empty() {
+        let mut table = HashTable::new(8);
+        table.insert("a", 1);
+        table.insert("b", 2);
+        table.remove("a");
+        let buckets = table.get_buckets();
+        assert_eq!(buckets.len(), table.get_size());
+        assert_eq!(buckets.iter().flatten().map(|entry| entry.value).collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn get_num_elements_is_len() {
+        let table: HashTable<usize> = [("a", 1), ("b", 2)].into_iter().collect();
+        assert_eq!(table.get_num_elements(), table.len());
+    }
+}
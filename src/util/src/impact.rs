@@ -0,0 +1,149 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::ops::AddAssign;
+
+use crate::constants::*;
+use crate::data_models::{DictRecord, QueryTerm, PostRecord, FileSizes};
+use crate::error::Error;
+use crate::hashtable::HashTable;
+use crate::postings::{boost_weight, read_post_lines};
+
+// A document's weight so far, and a bit for each term whose posting for it has been read
+#[derive(Clone)]
+struct Accumulator {
+    weight: usize,
+    seen: u64
+}
+
+impl AddAssign for Accumulator {
+    fn add_assign(&mut self, rhs: Self) {
+        self.weight += rhs.weight;
+        self.seen |= rhs.seen;
+    }
+}
+
+// One term's postings, read from highest to lowest weight a block at a time.
+// bound is the most any posting that hasn't been read yet can weigh
+struct ImpactList {
+    reader: BufReader<File>,
+    next: usize,
+    end: usize,
+    boost: f64,
+    bound: usize
+}
+
+impl ImpactList {
+    fn is_finished(&self) -> bool {
+        self.next >= self.end
+    }
+
+    fn read_block(&mut self, sizes: &FileSizes) -> Result<Vec<PostRecord>, Error> {
+        let block_end = self.end.min(self.next + POSTINGS_PER_SKIP);
+        let block = read_post_lines(&mut self.reader, self.next, block_end, self.boost, sizes)?;
+        self.next = block_end;
+        self.bound = if self.is_finished() { 0 } else { block.last().map_or(0, |posting| posting.weight) };
+        Ok(block)
+    }
+}
+
+// Queries can only use impact ordering when every term fits in an accumulator's seen bits
+pub fn can_use_impact_order(dict_records: &[(DictRecord, QueryTerm)]) -> bool {
+    dict_records.len() <= u64::BITS as usize
+}
+
+// The k best accumulated documents so far. Weights only grow, so a document that leaves never has to come back
+// until its weight is raised again, and the k-th weight is the threshold any other document has to beat
+struct TopK {
+    records: BTreeSet<PostRecord>,
+    k: usize
+}
+
+impl TopK {
+    fn update(&mut self, doc_id: usize, old_weight: Option<usize>, weight: usize) {
+        if let Some(old_weight) = old_weight {
+            self.records.remove(&PostRecord { doc_id, weight: old_weight });
+        }
+        self.records.insert(PostRecord { doc_id, weight });
+        if self.records.len() > self.k {
+            self.records.pop_first();
+        }
+    }
+
+    fn threshold(&self) -> Option<&PostRecord> {
+        if self.records.len() < self.k { None } else { self.records.first() }
+    }
+
+    fn contains(&self, doc_id: usize, weight: usize) -> bool {
+        self.records.contains(&PostRecord { doc_id, weight })
+    }
+}
+
+// The top k are settled once no document outside them, including any not seen yet, could end up ranked above the k-th.
+// Doc id 0 stands in for unseen documents since it wins every tie, and the documents that have been seen are only checked
+// once no unseen document could beat the threshold
+fn is_top_k_settled(accumulators: &HashTable<Accumulator>, top_k: &TopK, lists: &[ImpactList]) -> bool {
+    let unseen_bound = lists.iter().fold(0usize, |sum, list| sum.saturating_add(list.bound));
+    let kth = match top_k.threshold() {
+        Some(kth) => kth,
+        None => return unseen_bound == 0
+    };
+    if (PostRecord { doc_id: 0, weight: unseen_bound }) >= *kth { return false }
+    accumulators.iter().all(|entry| {
+        let doc_id = entry.key.parse().unwrap();
+        if top_k.contains(doc_id, entry.value.weight) { return true }
+        let upper_bound = lists.iter().enumerate()
+            .filter(|(term, _)| entry.value.seen & (1 << term) == 0)
+            .fold(entry.value.weight, |sum, (_, list)| sum.saturating_add(list.bound));
+        PostRecord { doc_id, weight: upper_bound } < *kth
+    })
+}
+
+// Score-at-a-time evaluation over impact ordered postings. The list with the highest remaining weights is always read next,
+// and reading stops once the top k can't change. Lists are then only read on where one of the top k documents hasn't been
+// seen in them yet, so the returned weights are exact. Documents in unread postings aren't counted, so the hit count is only
+// exact when every list was read before the top k settled, which is returned along with it
+pub fn impact_top_k(filedir: &str, dict_records: &[(DictRecord, QueryTerm)], sizes: &FileSizes, k: usize, accept: impl Fn(usize) -> bool) -> Result<(Vec<PostRecord>, usize, bool), Error> {
+    if k == 0 { return Ok((vec![], 0, false)) }
+    let mut lists = vec![];
+    for (record, term) in dict_records {
+        let reader = BufReader::new(File::open(format!("{filedir}/post"))?);
+        let bound = boost_weight(record.max_weight.unwrap_or(usize::MAX / dict_records.len()), term.boost);
+        let (next, end) = (record.post_line_start, record.post_line_start + record.num_docs);
+        lists.push(ImpactList { reader, next, end, boost: term.boost, bound });
+    }
+    let expected_docs = dict_records.iter().fold(0, |sum, (record, _)| sum + record.num_docs);
+    let mut accumulators: HashTable<Accumulator> = HashTable::new(expected_docs * 3 + 1);
+    let mut top_k = TopK { records: BTreeSet::new(), k };
+    while let Some(term) = (0..lists.len()).filter(|term| !lists[*term].is_finished()).max_by_key(|term| lists[*term].bound) {
+        for posting in lists[term].read_block(sizes)? {
+            if accept(posting.doc_id) {
+                let key = posting.doc_id.to_string();
+                let old_weight = accumulators.get(&key).map(|accumulator| accumulator.weight);
+                accumulators.insert_combine(&key, Accumulator { weight: posting.weight, seen: 1 << term });
+                let weight = old_weight.unwrap_or(0) + posting.weight;
+                top_k.update(posting.doc_id, old_weight, weight);
+            }
+        }
+        if is_top_k_settled(&accumulators, &top_k, &lists) { break }
+    }
+    let num_hits = accumulators.len();
+    let num_hits_exact = lists.iter().all(|list| list.is_finished());
+    let top_k: Vec<usize> = top_k.records.iter().rev().map(|record| record.doc_id).collect();
+    for (term, list) in lists.iter_mut().enumerate() {
+        let is_unresolved = |accumulators: &HashTable<Accumulator>| top_k.iter()
+            .any(|doc_id| accumulators.get(&doc_id.to_string()).is_some_and(|accumulator| accumulator.seen & (1 << term) == 0));
+        while !list.is_finished() && is_unresolved(&accumulators) {
+            for posting in list.read_block(sizes)? {
+                if top_k.contains(&posting.doc_id) {
+                    accumulators.insert_combine(&posting.doc_id.to_string(), Accumulator { weight: posting.weight, seen: 1 << term });
+                }
+            }
+        }
+    }
+    let mut results: Vec<PostRecord> = top_k.iter()
+        .map(|doc_id| PostRecord { doc_id: *doc_id, weight: accumulators.get(&doc_id.to_string()).map_or(0, |accumulator| accumulator.weight) })
+        .collect();
+    results.sort_by(|a, b| b.cmp(a));
+    Ok((results, num_hits, num_hits_exact))
+}
@@ -1,6 +1,20 @@
 pub mod constants;
 pub mod data_models;
+pub mod dates;
+pub mod documents;
+pub mod error;
+pub mod explain;
+pub mod facets;
 pub mod hashtable;
+pub mod impact;
+pub mod max_score;
 pub mod parser;
+pub mod postings;
+pub mod query_parser;
 pub mod read_query_files;
+pub mod runs;
+pub mod sharded_hashtable;
+pub mod snippets;
+pub mod store_spool;
+pub mod suggest;
 pub mod write_query_files;
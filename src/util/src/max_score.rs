@@ -0,0 +1,90 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::data_models::PostRecord;
+use crate::error::Error;
+use crate::postings::PostingCursor;
+
+// Keeps the k best results seen so far, returning the weight a new result has to beat once there are k of them
+struct TopK {
+    heap: BinaryHeap<Reverse<PostRecord>>,
+    k: usize
+}
+
+impl TopK {
+    fn push(&mut self, result: PostRecord) -> Option<usize> {
+        if self.heap.len() < self.k {
+            self.heap.push(Reverse(result));
+        }
+        else if let Some(Reverse(heap_head)) = self.heap.peek() {
+            if *heap_head < result {
+                self.heap.pop();
+                self.heap.push(Reverse(result));
+            }
+        }
+        self.threshold()
+    }
+
+    fn threshold(&self) -> Option<usize> {
+        if self.heap.len() < self.k { return None }
+        self.heap.peek().map(|Reverse(heap_head)| heap_head.weight)
+    }
+
+    fn into_sorted_vec(self) -> Vec<PostRecord> {
+        self.heap.into_sorted_vec().into_iter().map(|Reverse(record)| record).collect()
+    }
+}
+
+// Document-at-a-time MaxScore. Terms are ordered by their max weight, and the lowest ones whose max weights together can't beat
+// the current k-th best score are non-essential: a document is only scored when it appears in an essential term, and the
+// non-essential terms are only checked while they could still lift it into the top k.
+// Documents that only appear in non-essential terms are never visited, so the returned hit count is only exact when no term
+// was ever non-essential, which is returned along with it
+pub fn max_score_top_k(mut cursors: Vec<PostingCursor>, k: usize, accept: impl Fn(usize) -> bool) -> Result<(Vec<PostRecord>, usize, bool), Error> {
+    cursors.sort_by_key(|cursor| cursor.max_weight);
+    let upper_bounds: Vec<usize> = cursors.iter().scan(0, |sum, cursor| {
+        *sum += cursor.max_weight;
+        Some(*sum)
+    }).collect();
+    let mut top_k = TopK { heap: BinaryHeap::new(), k };
+    let mut first_essential = 0;
+    let mut num_hits = 0;
+    while let Some(doc_id) = cursors[first_essential..].iter().filter_map(|cursor| cursor.doc_id()).min() {
+        let mut weight = 0;
+        for cursor in &mut cursors[first_essential..] {
+            if let Some(posting) = cursor.current().filter(|posting| posting.doc_id == doc_id) {
+                weight += posting.weight;
+                cursor.step()?;
+            }
+        }
+        if !accept(doc_id) { continue }
+        num_hits += 1;
+        for term in (0..first_essential).rev() {
+            if top_k.threshold().is_some_and(|threshold| weight + upper_bounds[term] <= threshold) { break }
+            weight += cursors[term].weight_at(doc_id)?.unwrap_or(0);
+        }
+        if let Some(threshold) = top_k.push(PostRecord { doc_id, weight }) {
+            while first_essential < cursors.len() && upper_bounds[first_essential] <= threshold {
+                first_essential += 1;
+            }
+        }
+    }
+    Ok((top_k.into_sorted_vec(), num_hits, first_essential == 0))
+}
+
+// Scores only the documents containing every required term, found by intersecting their lists beforehand,
+// so the other terms' lists are jumped through rather than read in full. Every match is visited, so the hit count is exact
+pub fn required_top_k(mut cursors: Vec<PostingCursor>, matches: &[usize], k: usize, accept: impl Fn(usize) -> bool) -> Result<(Vec<PostRecord>, usize, bool), Error> {
+    let mut top_k = TopK { heap: BinaryHeap::new(), k };
+    let mut num_hits = 0;
+    for &doc_id in matches {
+        if !accept(doc_id) { continue }
+        num_hits += 1;
+        let mut weight = 0;
+        for cursor in &mut cursors {
+            weight += cursor.weight_at(doc_id)?.unwrap_or(0);
+        }
+        top_k.push(PostRecord { doc_id, weight });
+    }
+    Ok((top_k.into_sorted_vec(), num_hits, true))
+}
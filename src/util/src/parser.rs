@@ -1,8 +1,10 @@
+use std::{mem::take, ops::Range};
 use logos::{Logos};
 use regex::Regex;
 use lazy_static::lazy_static;
 use crate::constants::*;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Logos, Debug, PartialEq)]
 enum Token<'a> {
     // CSS Tags take the form: element1, element2, .. elementN { ** CSS ** }
@@ -67,19 +69,73 @@ fn clean_non_ascii(lex: &str) -> String {
     RE_CLEAN_NON_ASCII.replace_all(lex, "").to_string().to_ascii_lowercase()
 }
 fn clean_link(lex: &str) -> String {
-    clean_non_ascii(&RE_CLEAN_LINK.replace_all(lex, "").to_string())
+    clean_non_ascii(RE_CLEAN_LINK.replace_all(lex, "").as_ref())
 }
 fn clean_email(lex: &str) -> String {
-    clean_non_ascii(&RE_CLEAN_EMAIL.replace_all(lex, "").to_string())
+    clean_non_ascii(RE_CLEAN_EMAIL.replace_all(lex, "").as_ref())
 }
 fn clean_number(lex: &str) -> String {
-    clean_non_ascii(&RE_CLEAN_NUM.replace_all(lex, "").to_string())
+    clean_non_ascii(RE_CLEAN_NUM.replace_all(lex, "").as_ref())
 }
 fn clean_word(lex: &str) -> String {
-    clean_non_ascii(&RE_CLEAN_WORD.replace_all(lex, "").to_string())
+    clean_non_ascii(RE_CLEAN_WORD.replace_all(lex, "").as_ref())
+}
+
+// Parses text that arrives in chunks, such as a large file read a piece at a time. The end of each chunk that could be part of
+// a token carrying on into the next chunk is held back and parsed with it. Tokens only contain whitespace inside an html tag
+#[derive(Default)]
+pub struct ChunkParser {
+    carry: String
+}
+
+impl ChunkParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse_chunk(&mut self, chunk: &str) -> Vec<String> {
+        self.carry.push_str(chunk);
+        let cut = find_chunk_cut(&self.carry);
+        let tokens = parse(&self.carry[..cut]);
+        self.carry.drain(..cut);
+        tokens
+    }
+
+    // Parses whatever was held back from the last chunk
+    pub fn finish(&mut self) -> Vec<String> {
+        parse(&take(&mut self.carry))
+    }
+}
+
+// Where to split text so no token crosses the split, which is after the last whitespace that isn't between a < and a >.
+// If that would hold back more than MAX_CARRY_BYTES, the < is taken to be a stray one and the last whitespace is used instead,
+// and if even that is too far back the token is assumed never to end and the text is split at the end
+fn find_chunk_cut(text: &str) -> usize {
+    let mut in_tag = false;
+    let mut outside_tag_cut = 0;
+    let mut whitespace_cut = 0;
+    for (position, byte) in text.bytes().enumerate() {
+        match byte {
+            b'<' => in_tag = true,
+            b'>' => in_tag = false,
+            byte if byte.is_ascii_whitespace() => {
+                whitespace_cut = position + 1;
+                if !in_tag { outside_tag_cut = position + 1 }
+            }
+            _ => ()
+        }
+    }
+    [outside_tag_cut, whitespace_cut].into_iter()
+        .find(|cut| text.len() - cut <= MAX_CARRY_BYTES)
+        .unwrap_or(text.len())
 }
 
 pub fn parse(text: &str) -> Vec<String> {
+    parse_with_spans(text).into_iter().map(|(token, _)| token).collect()
+}
+
+// Same as parse, but also returns the byte range each token was read from in the original text
+pub fn parse_with_spans(text: &str) -> Vec<(String, Range<usize>)> {
     let mut vector = vec![];
     let mut lex = Token::lexer(text);
     while let Some(tok) = lex.next() {
@@ -91,7 +147,65 @@ pub fn parse(text: &str) -> Vec<String> {
             _ => continue
         };
         cleaned_tok.truncate(TERM_LENGTH);
-        vector.push(cleaned_tok);
+        vector.push((cleaned_tok, lex.span()));
     }
     vector
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: [&str; 5] = [
+        "<html><head><title>A  page</title></head><body class=\"main page\"><b>E</b>lephants we'll see</body></html>",
+        "<!-- a comment with spaces --> <a href = \"https://example.com/a b\" >link</a> <br/> after",
+        "visit https://www.example.com/path?q=1 or www.rust-lang.org/learn today, http://x.io",
+        "mail someone@example.com or Other.Person@mail.co.uk at 1,234.56 or 555-1234 now",
+        "café naïve 日本語 résumé <em>über</em>größe 🦀 crab’s Ph.D 20.07 \u{a0}nbsp\u{85}next"
+    ];
+
+    fn parse_in_chunks(chunks: &[&str]) -> Vec<String> {
+        let mut parser = ChunkParser::new();
+        let mut tokens = vec![];
+        for chunk in chunks {
+            tokens.extend(parser.parse_chunk(chunk));
+        }
+        tokens.extend(parser.finish());
+        tokens
+    }
+
+    fn char_boundaries(text: &str) -> impl Iterator<Item = usize> + '_ {
+        (0..=text.len()).filter(|offset| text.is_char_boundary(*offset))
+    }
+
+    #[test]
+    fn splitting_once_anywhere_matches_parse() {
+        for text in SAMPLES {
+            for offset in char_boundaries(text) {
+                let (first, second) = text.split_at(offset);
+                assert_eq!(parse_in_chunks(&[first, second]), parse(text), "split at {offset} of {text:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn small_chunks_match_parse() {
+        for text in SAMPLES {
+            for step in 1..=8 {
+                let mut cuts: Vec<usize> = char_boundaries(text).step_by(step).collect();
+                if cuts.last() != Some(&text.len()) { cuts.push(text.len()) }
+                let chunks: Vec<&str> = cuts.windows(2).map(|cut| &text[cut[0]..cut[1]]).collect();
+                assert_eq!(chunks.concat(), text);
+                assert_eq!(parse_in_chunks(&chunks), parse(text), "chunks of {step} characters of {text:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn all_samples_together_match_parse() {
+        let text = SAMPLES.join("\n");
+        for offset in char_boundaries(&text) {
+            let (first, second) = text.split_at(offset);
+            assert_eq!(parse_in_chunks(&[first, second]), parse(&text), "split at {offset}");
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom, BufRead};
+
+use crate::constants::*;
+use crate::data_models::{DictRecord, PostRecord, FileSizes};
+use crate::error::{Error, parse_field};
+
+pub(crate) fn boost_weight(weight: usize, boost: f64) -> usize {
+    if boost == 1.0 { return weight }
+    (weight as f64 * boost).round() as usize
+}
+
+pub(crate) fn read_post_lines(reader: &mut BufReader<File>, start: usize, end: usize, boost: f64, sizes: &FileSizes) -> Result<Vec<PostRecord>, Error> {
+    let mut post_records = vec![];
+    reader.seek(SeekFrom::Start((start * sizes.get_post_record_size()) as u64))?;
+    for _ in start..end {
+        let mut record_str = String::new();
+        reader.read_line(&mut record_str)?;
+        let mut split_record = record_str.split_whitespace();
+        let doc_id = parse_field(split_record.next(), "post")?;
+        let weight: usize = parse_field(split_record.next(), "post")?;
+        post_records.push(PostRecord { doc_id, weight: boost_weight(weight, boost) })
+    }
+    Ok(post_records)
+}
+
+// A run of post lines that is read in one go. Segments end at every skip block boundary, and first_doc_id is the
+// doc id from the skips file, or 0 for the start of the list where the boundary isn't known
+struct Segment {
+    start: usize,
+    end: usize,
+    first_doc_id: usize
+}
+
+// Walks one term's postings in doc id order, reading them from the post file one segment at a time.
+// Indexes written before postings were sorted have their whole list read and sorted up front instead
+pub struct PostingCursor {
+    reader: Option<BufReader<File>>,
+    sizes: FileSizes,
+    boost: f64,
+    segments: Vec<Segment>,
+    segment: usize,
+    postings: Vec<PostRecord>,
+    position: usize,
+    pub max_weight: usize
+}
+
+impl PostingCursor {
+    pub fn from_postings(mut postings: Vec<PostRecord>, max_weight: usize) -> Self {
+        postings.sort_by_key(|posting| posting.doc_id);
+        let segments = vec![Segment { start: 0, end: postings.len(), first_doc_id: 0 }];
+        Self { reader: None, sizes: FileSizes::default(), boost: 1.0, segments, segment: 0, postings, position: 0, max_weight }
+    }
+
+    pub fn open(filedir: &str, dict_record: &DictRecord, boost: f64, sizes: &FileSizes) -> Result<Self, Error> {
+        let start = dict_record.post_line_start;
+        let end = start + dict_record.num_docs;
+        let first_skip = start.div_ceil(POSTINGS_PER_SKIP);
+        let last_skip = end.div_ceil(POSTINGS_PER_SKIP);
+        let mut segments = vec![Segment { start, end: end.min(first_skip * POSTINGS_PER_SKIP), first_doc_id: 0 }];
+        let mut skips_reader = BufReader::new(File::open(format!("{filedir}/skips"))?);
+        skips_reader.seek(SeekFrom::Start((first_skip * sizes.get_skip_record_size()) as u64))?;
+        for skip in first_skip..last_skip {
+            let mut record_str = String::new();
+            skips_reader.read_line(&mut record_str)?;
+            let segment_start = skip * POSTINGS_PER_SKIP;
+            let segment_end = end.min(segment_start + POSTINGS_PER_SKIP);
+            segments.push(Segment { start: segment_start, end: segment_end, first_doc_id: parse_field(Some(&record_str), "skips")? });
+        }
+        segments.retain(|segment| segment.start < segment.end);
+        let reader = BufReader::new(File::open(format!("{filedir}/post"))?);
+        let max_weight = boost_weight(dict_record.max_weight.unwrap_or(0), boost);
+        let mut cursor = Self { reader: Some(reader), sizes: sizes.clone(), boost, segments, segment: 0, postings: vec![], position: 0, max_weight };
+        cursor.load_segment(0)?;
+        Ok(cursor)
+    }
+
+    fn load_segment(&mut self, segment: usize) -> Result<(), Error> {
+        self.segment = segment;
+        self.position = 0;
+        self.postings = match (&mut self.reader, self.segments.get(segment)) {
+            (Some(reader), Some(Segment { start, end, .. })) => read_post_lines(reader, *start, *end, self.boost, &self.sizes)?,
+            _ => vec![]
+        };
+        Ok(())
+    }
+
+    // Moves on to the next segment when the loaded one has been used up
+    fn settle(&mut self) -> Result<(), Error> {
+        while self.position >= self.postings.len() && self.segment + 1 < self.segments.len() {
+            self.load_segment(self.segment + 1)?;
+        }
+        Ok(())
+    }
+
+    pub fn current(&self) -> Option<&PostRecord> {
+        self.postings.get(self.position)
+    }
+
+    pub fn doc_id(&self) -> Option<usize> {
+        self.current().map(|posting| posting.doc_id)
+    }
+
+    pub fn step(&mut self) -> Result<(), Error> {
+        self.position += 1;
+        self.settle()
+    }
+
+    // Moves to the first posting at or after doc_id. Segments that end before it are skipped without being read,
+    // then the search within a segment gallops so a long skip doesn't look at every posting in between
+    pub fn advance_to(&mut self, doc_id: usize) -> Result<(), Error> {
+        if self.doc_id().is_none_or(|current| current >= doc_id) { return Ok(()) }
+        if self.postings.last().is_some_and(|last| last.doc_id < doc_id) {
+            let later = &self.segments[self.segment + 1..];
+            let skipped = later.partition_point(|segment| segment.first_doc_id <= doc_id);
+            if self.segment + skipped.max(1) >= self.segments.len() {
+                self.position = self.postings.len();
+                return Ok(())
+            }
+            self.load_segment(self.segment + skipped.max(1))?;
+        }
+        let remaining = &self.postings[self.position..];
+        let (mut low, mut high) = (0, 1);
+        while high < remaining.len() && remaining[high].doc_id < doc_id {
+            low = high;
+            high *= 2;
+        }
+        let end = (high + 1).min(remaining.len());
+        self.position += low + remaining[low..end].partition_point(|posting| posting.doc_id < doc_id);
+        self.settle()
+    }
+
+    pub fn weight_at(&mut self, doc_id: usize) -> Result<Option<usize>, Error> {
+        self.advance_to(doc_id)?;
+        Ok(self.current().filter(|posting| posting.doc_id == doc_id).map(|posting| posting.weight))
+    }
+}
+
+// Leapfrogs through the lists, each one jumping ahead to the largest doc id seen so far until they all agree
+pub fn intersect(cursors: &mut [PostingCursor]) -> Result<Vec<usize>, Error> {
+    let mut matches = vec![];
+    if cursors.is_empty() { return Ok(matches) }
+    'search: loop {
+        let mut candidate = 0;
+        for cursor in cursors.iter() {
+            match cursor.doc_id() {
+                Some(doc_id) => candidate = candidate.max(doc_id),
+                None => break 'search
+            }
+        }
+        let mut agreed = true;
+        for cursor in cursors.iter_mut() {
+            cursor.advance_to(candidate)?;
+            if cursor.doc_id() != Some(candidate) { agreed = false }
+        }
+        if agreed {
+            matches.push(candidate);
+            for cursor in cursors.iter_mut() { cursor.step()? }
+        }
+    }
+    Ok(matches)
+}
@@ -0,0 +1,159 @@
+use std::mem::discriminant;
+use std::path::Path;
+
+use crate::data_models::MapRecord;
+use crate::dates::parse_period;
+use crate::error::Error;
+use crate::constants::MAX_BOOST;
+use crate::parser::parse;
+
+// Bounds for size and modified filters are inclusive
+pub enum Filter {
+    Extension(String),
+    Directory(String),
+    Size(u64, u64),
+    Modified(u64, u64)
+}
+
+impl Filter {
+    // None when the clause isn't a filter at all. A filter whose value doesn't parse is an error rather than being searched
+    // as text, which would drop the filter and widen the results
+    fn parse(clause: &str) -> Result<Option<Self>, Error> {
+        let Some((field, value)) = clause.split_once(':') else { return Ok(None) };
+        let field = field.to_ascii_lowercase();
+        let filter = match field.as_str() {
+            "ext" | "dir" | "size" | "modified" if value.is_empty() => None,
+            "ext" => Some(Filter::Extension(value.trim_start_matches('.').to_ascii_lowercase())),
+            "dir" => Some(Filter::Directory(value.trim_start_matches("./").trim_matches('/').to_string())),
+            "size" => parse_bounds(value, parse_size).map(|(min, max)| Filter::Size(min, max)),
+            "modified" => parse_bounds(value, parse_period).map(|(min, max)| Filter::Modified(min, max)),
+            _ => return Ok(None)
+        };
+        filter.map(Some).ok_or_else(|| Error::QuerySyntax(format!("\"{value}\" isn't a valid {field} filter")))
+    }
+
+    fn matches(&self, record: &MapRecord) -> bool {
+        match self {
+            Filter::Extension(ext) => {
+                let record_ext = Path::new(&record.file_name).extension().and_then(|record_ext| record_ext.to_str()).unwrap_or("");
+                record_ext.eq_ignore_ascii_case(ext)
+            }
+            Filter::Directory(dir) => record.file_name.starts_with(&format!("{dir}/")),
+            Filter::Size(min, max) => (*min..=*max).contains(&record.size),
+            Filter::Modified(min, max) => (*min..=*max).contains(&record.modified)
+        }
+    }
+}
+
+// Splits a leading comparison operator from a filter value, then widens the value's own range accordingly
+fn parse_bounds(value: &str, parse_value: fn(&str) -> Option<(u64, u64)>) -> Option<(u64, u64)> {
+    let operator_length = value.find(|c: char| !"<>=".contains(c)).unwrap_or(value.len());
+    let (operator, value) = value.split_at(operator_length);
+    let (start, end) = parse_value(value)?;
+    match operator {
+        "<" => Some((0, start.checked_sub(1)?)),
+        "<=" => Some((0, end)),
+        ">" => Some((end.checked_add(1)?, u64::MAX)),
+        ">=" => Some((start, u64::MAX)),
+        "" | "=" => Some((start, end)),
+        _ => None
+    }
+}
+
+fn parse_size(value: &str) -> Option<(u64, u64)> {
+    let unit_start = value.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_start);
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        _ => return None
+    };
+    let bytes = (number.parse::<f64>().ok()? * multiplier as f64) as u64;
+    Some((bytes, bytes))
+}
+
+// A piece of query text and how much more its terms should count than the rest, so "rust^3" becomes ("rust", 3.0).
+// A leading + ("+rust") means every matching document must contain its terms. Boosts over MAX_BOOST are rejected, since
+// the boosted weights of a few terms could then add up to more than a usize holds
+pub struct QueryClause {
+    pub text: String,
+    pub boost: f64,
+    pub required: bool
+}
+
+impl QueryClause {
+    fn parse(clause: &str) -> Result<Self, Error> {
+        let (clause, required) = match clause.strip_prefix('+') {
+            Some(rest) if !rest.is_empty() => (rest, true),
+            _ => (clause, false)
+        };
+        let boosted = clause.rsplit_once('^').and_then(|(text, boost)| {
+            let boost: f64 = boost.parse().ok()?;
+            if text.is_empty() || !boost.is_finite() || boost <= 0.0 { return None }
+            Some((text, boost))
+        });
+        let (text, boost) = boosted.unwrap_or((clause, 1.0));
+        if boost > MAX_BOOST {
+            return Err(Error::QuerySyntax(format!("the boost on \"{clause}\" is over the maximum of {MAX_BOOST}")))
+        }
+        Ok(QueryClause { text: text.to_string(), boost, required })
+    }
+}
+
+pub struct ParsedQuery {
+    pub text: String,
+    pub clauses: Vec<QueryClause>,
+    pub filters: Vec<Filter>
+}
+
+impl ParsedQuery {
+    // Filters of the same kind are alternatives (ext:html ext:txt), while filters of different kinds must all match
+    pub fn matches(&self, record: &MapRecord) -> bool {
+        self.filters.iter().all(|filter| {
+            self.filters.iter()
+                .filter(|other| discriminant(*other) == discriminant(filter))
+                .any(|other| other.matches(record))
+        })
+    }
+}
+
+// Anything that isn't a recognised filter clause is left in the text to be tokenized as normal, without any boost suffix
+pub fn parse_query(query: &str) -> Result<ParsedQuery, Error> {
+    let mut clauses = vec![];
+    let mut filters = vec![];
+    for clause in query.split_whitespace() {
+        match Filter::parse(clause)? {
+            Some(filter) => filters.push(filter),
+            None => clauses.push(QueryClause::parse(clause)?)
+        }
+    }
+    let text = clauses.iter().map(|clause| clause.text.as_str()).collect::<Vec<&str>>().join(" ");
+    Ok(ParsedQuery { text, clauses, filters })
+}
+
+// The query as it is actually searched for, so queries that only differ in case, punctuation or the order of their filters
+// are written the same way. Tokens keep their order and any + or boost, and filter clauses are sorted after them
+pub fn normalize_query(query: &str) -> String {
+    let mut terms = vec![];
+    let mut filters = vec![];
+    for clause in query.split_whitespace() {
+        if !matches!(Filter::parse(clause), Ok(None)) {
+            filters.push(clause.to_string());
+            continue
+        }
+        let Ok(clause) = QueryClause::parse(clause) else {
+            terms.push(clause.to_string());
+            continue
+        };
+        for token in parse(&clause.text) {
+            let required = if clause.required { "+" } else { "" };
+            let boost = if clause.boost == 1.0 { String::new() } else { format!("^{}", clause.boost) };
+            terms.push(format!("{required}{token}{boost}"));
+        }
+    }
+    filters.sort();
+    terms.append(&mut filters);
+    terms.join(" ")
+}
@@ -1,112 +1,236 @@
-use std::cmp::Reverse;
+use std::cmp::{Reverse, Ordering};
 use std::collections::BinaryHeap;
 use std::fs::{File, self};
-use std::io::{Error, BufReader, Seek, SeekFrom, BufRead};
+use std::io::{BufReader, Seek, SeekFrom, BufRead, Read, ErrorKind};
+use std::time::UNIX_EPOCH;
+use std::sync::{Arc, Mutex};
+
+use flate2::read::DeflateDecoder;
+use lazy_static::lazy_static;
 
 use std::vec;
 
+use crate::documents::{read_document_head, truncate_text};
 use crate::parser::parse;
-use crate::data_models::{DictRecord, PostRecord, NamedResult, FileSizes};
-use crate::hashtable::{hash_function, rehash, HashTable};
+use crate::data_models::{DictRecord, QueryTerm, PostRecord, NamedResult, FileSizes, IndexMetadata, PostingsOrder, Snippet, StoredDoc, MapRecord, QueryOptions, QueryResults, TieBreak, Sort, SortField};
+use crate::snippets::make_snippet;
+use crate::query_parser::{parse_query, ParsedQuery};
+use crate::facets::count_facets;
+use crate::explain::add_explanations;
+use crate::max_score::{max_score_top_k, required_top_k};
+use crate::impact::{impact_top_k, can_use_impact_order};
+use crate::postings::{boost_weight, read_post_lines, intersect, PostingCursor};
+use crate::error::{Error, parse_field};
+use crate::constants::*;
+use crate::hashtable::{rehash, HashTable};
 
-fn get_query_tokens(query: &str) -> Vec<String> {
-    return parse(query);
+// Repeated tokens are merged so each term is only looked up once. An explicit boost or + applies to every token of its clause,
+// the largest boost is kept for a repeated token, and the query term frequency adds a damped factor of 1 + log10(qtf)
+fn get_query_terms(parsed_query: &ParsedQuery) -> Vec<QueryTerm> {
+    let mut terms: Vec<(QueryTerm, usize)> = vec![];
+    for clause in &parsed_query.clauses {
+        for token in parse(&clause.text) {
+            match terms.iter_mut().find(|(term, _)| term.token == token) {
+                Some((term, count)) => {
+                    term.boost = term.boost.max(clause.boost);
+                    term.required |= clause.required;
+                    *count += 1;
+                }
+                None => terms.push((QueryTerm { token, boost: clause.boost, required: clause.required }, 1))
+            }
+        }
+    }
+    terms.into_iter()
+        .map(|(term, count)| QueryTerm { boost: term.boost * (1.0 + (count as f64).log10()), ..term })
+        .collect()
 }
 
-fn get_sizes(filedir: &str) -> Result<FileSizes, Error> {
+pub(crate) fn get_sizes(filedir: &str) -> Result<FileSizes, Error> {
     let file_contents = fs::read_to_string(format!("{filedir}/sizes"))?;
     let sizes: FileSizes = serde_json::from_str(&file_contents)?;
     Ok(sizes)
 }
 
-fn get_dict_records(filedir: &str, tokens: &Vec<String>, sizes: &FileSizes) -> Result<Vec<DictRecord>, Error> {
+// Indexes written before the metadata file existed fall back to the defaults
+pub(crate) fn get_metadata(filedir: &str) -> Result<IndexMetadata, Error> {
+    let file_contents = match fs::read_to_string(format!("{filedir}/metadata")) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(IndexMetadata::default()),
+        Err(error) => return Err(error.into())
+    };
+    let metadata: IndexMetadata = serde_json::from_str(&file_contents)?;
+    Ok(metadata)
+}
+
+// Changes whenever the index is rebuilt. Indexes written before generations were recorded use the sizes file's modification time
+pub fn get_index_generation(filedir: &str) -> Result<u64, Error> {
+    get_generation(filedir, &get_metadata(filedir)?)
+}
+
+fn get_generation(filedir: &str, metadata: &IndexMetadata) -> Result<u64, Error> {
+    if metadata.generation > 0 { return Ok(metadata.generation) }
+    let modified = fs::metadata(format!("{filedir}/sizes"))?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_millis() as u64))
+}
+
+// Each record is paired with the query term it was found for
+fn get_dict_records(filedir: &str, terms: &[QueryTerm], metadata: &IndexMetadata, sizes: &FileSizes) -> Result<Vec<(DictRecord, QueryTerm)>, Error> {
     let mut records = vec![];
     let file = File::open(format!("{filedir}/dict"))?;
     let mut reader = BufReader::new(file);
-    for token in tokens {
-        if let Some(record) = get_one_dict_record(&mut reader, token, &sizes)? {
-            records.push(record);
+    for term in terms {
+        if let Some(record) = get_one_dict_record(&mut reader, &term.token, metadata, sizes)? {
+            records.push((record, term.clone()));
         }
     }
     Ok(records)
 }
 
-fn get_one_dict_record(reader: &mut BufReader<File>, token: &str, sizes: &FileSizes) -> Result<Option<DictRecord>, Error> {
-    let mut hash = hash_function(token, &sizes.num_dict_lines).unwrap();
-    let mut record = read_one_dict_line_from_hash(reader, &sizes, hash)?;
-    while record.term != "!NULL" && record.term != token { 
+// Probing stops at the recorded max probe length, since no term is further than that from where it hashes to.
+// Older indexes don't record it, so the probe is instead stopped once it comes back around to where it started
+fn get_one_dict_record(reader: &mut BufReader<File>, token: &str, metadata: &IndexMetadata, sizes: &FileSizes) -> Result<Option<DictRecord>, Error> {
+    if sizes.num_dict_lines == 0 { return Err(Error::CorruptIndex("dict has no lines".to_string())) }
+    let start = metadata.dict_hash_function.hash(token, sizes.num_dict_lines);
+    let max_probes = if metadata.max_dict_probes > 0 { metadata.max_dict_probes } else { sizes.num_dict_lines };
+    let mut hash = start;
+    for _ in 0..max_probes {
+        let record = read_one_dict_line_from_hash(reader, sizes, hash)?;
+        if record.term == token { return Ok(Some(record)) }
+        if record.term == "!NULL" { return Ok(None) }
         hash = rehash(&hash, &sizes.num_dict_lines);
-        record = read_one_dict_line_from_hash(reader, &sizes, hash)?;
+        if hash == start { break }
+    }
+    match metadata.max_dict_probes {
+        0 => Err(Error::CorruptIndex(format!("dict lookup for {token} checked every line it can reach without finding an empty one"))),
+        _ => Ok(None)
     }
-    if record.term.starts_with("!") { return Ok(None); }
-    Ok(Some(record))
 }
 
 fn read_one_dict_line_from_hash(reader: &mut BufReader<File>, sizes: &FileSizes, hash: usize) -> Result<DictRecord, Error> {
-    reader.seek(SeekFrom::Start((hash * sizes.get_dict_record_size()).try_into().unwrap()))?;
+    reader.seek(SeekFrom::Start((hash * sizes.get_dict_record_size()) as u64))?;
     let mut record_str = String::new();
     reader.read_line(&mut record_str)?;
-    let split_record: Vec<&str> = record_str.split_whitespace().collect();
-    let term = split_record[0];
-    let num_docs = split_record[1].parse().unwrap();
-    let start = split_record[2].parse().unwrap();
-    Ok(DictRecord { term: term.to_string(), num_docs: num_docs, post_line_start: start })
+    let mut split_record = record_str.split_whitespace();
+    let term = parse_field(split_record.next(), "dict")?;
+    let num_docs = parse_field(split_record.next(), "dict")?;
+    let start = parse_field(split_record.next(), "dict")?;
+    let max_weight = match split_record.next() {
+        Some(max_weight) => Some(parse_field(Some(max_weight), "dict")?),
+        None => None
+    };
+    Ok(DictRecord { term, num_docs, post_line_start: start, max_weight })
 }
 
-fn make_query_ht(post_records: &Vec<PostRecord>, expected_docs: usize) -> HashTable<usize> {
+fn make_query_ht(term_postings: &[Vec<PostRecord>], expected_docs: usize) -> HashTable<usize> {
     let mut query_ht = HashTable::new(expected_docs * 3);
-    for record in post_records {
+    for record in term_postings.iter().flatten() {
         query_ht.insert_combine(&record.doc_id.to_string(), record.weight);
     }
     query_ht
 }
 
-fn get_all_post_records(filedir: &str, dict_records: &Vec<DictRecord>, sizes: &FileSizes) -> Result<Vec<PostRecord>, Error> {
+// Postings are kept grouped by term, in the same order as the dict records, with their weights already boosted
+fn get_all_post_records(filedir: &str, dict_records: &[(DictRecord, QueryTerm)], sizes: &FileSizes) -> Result<Vec<Vec<PostRecord>>, Error> {
     let file = File::open(format!("{filedir}/post"))?;
     let mut reader = BufReader::new(file);
-    let mut post_records = vec![];
-    for dict_record in dict_records {
-        post_records.append(&mut get_term_post_records(&mut reader, &dict_record, sizes)?);
+    let mut term_postings = vec![];
+    for (dict_record, term) in dict_records {
+        term_postings.push(get_term_post_records(&mut reader, dict_record, term.boost, sizes)?);
     }
-    Ok(post_records)
+    Ok(term_postings)
 }
 
-fn get_term_post_records(reader: &mut BufReader<File>, dict_record: &DictRecord, sizes: &FileSizes) -> Result<Vec<PostRecord>, Error> {
-    let mut post_records = vec![];
-    reader.seek(SeekFrom::Start((dict_record.post_line_start * sizes.get_post_record_size()).try_into().unwrap()))?;
-    for _ in 0..dict_record.num_docs {
-        let mut record_str = String::new();
-        reader.read_line(&mut record_str)?;
-        let split_record: Vec<&str> = record_str.split_whitespace().collect();
-        let doc_id = split_record[0].parse().unwrap();
-        let weight: usize = split_record[1].parse().unwrap();
-        post_records.push(PostRecord { doc_id: doc_id, weight: weight })
-    }
-    Ok(post_records)
-}
-
-fn get_sorted_results(query_ht: HashTable<usize>, num_results: usize) -> Vec<PostRecord> {
-    let mut heap: BinaryHeap<Reverse<PostRecord>> = BinaryHeap::new();
-    for bucket in query_ht.get_buckets() {
-        if let Some(entry) = bucket {
-            match heap.peek() {
-                Some(Reverse(heap_head)) => {
-                    if heap.len() < num_results {
-                        heap.push(Reverse(PostRecord { doc_id: entry.key.parse().unwrap(), weight: entry.value }));
-                    }
-                    else if heap_head.weight < entry.value {
-                        heap.pop();
-                        heap.push(Reverse(PostRecord { doc_id: entry.key.parse().unwrap(), weight: entry.value }));
-                    }
-                }
-                None => heap.push(Reverse(PostRecord { doc_id: entry.key.parse().unwrap(), weight: entry.value }))
+fn get_num_docs(filedir: &str, sizes: &FileSizes) -> Result<usize, Error> {
+    let map_length = fs::metadata(format!("{filedir}/map"))?.len() as usize;
+    Ok(map_length / sizes.get_map_record_size())
+}
+
+fn get_term_post_records(reader: &mut BufReader<File>, dict_record: &DictRecord, boost: f64, sizes: &FileSizes) -> Result<Vec<PostRecord>, Error> {
+    let start = dict_record.post_line_start;
+    read_post_lines(reader, start, start + dict_record.num_docs, boost, sizes)
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SortValue {
+    DocId,
+    Number(u64),
+    Text(String)
+}
+
+impl SortValue {
+    fn from_tie_break(tie_break: TieBreak, record: Option<&MapRecord>) -> Self {
+        match (tie_break, record) {
+            (TieBreak::Name, Some(record)) => SortValue::Text(record.file_name.clone()),
+            (TieBreak::Modified, Some(record)) => SortValue::Number(record.modified),
+            (TieBreak::Size, Some(record)) => SortValue::Number(record.size),
+            _ => SortValue::DocId
+        }
+    }
+
+    fn from_sort(field: SortField, weight: usize, record: Option<&MapRecord>) -> Self {
+        match (field, record) {
+            (SortField::Name, Some(record)) => SortValue::Text(record.file_name.clone()),
+            (SortField::Modified, Some(record)) => SortValue::Number(record.modified),
+            (SortField::Size, Some(record)) => SortValue::Number(record.size),
+            _ => SortValue::Number(weight as u64)
+        }
+    }
+}
+
+// Greater is better: the sort value in the requested direction, then higher weight, then the smaller tie break value, then the lower doc id
+#[derive(PartialEq, Eq)]
+struct RankedResult {
+    record: PostRecord,
+    sort_value: SortValue,
+    descending: bool,
+    tie_value: SortValue
+}
+
+impl Ord for RankedResult {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let sort_order = match self.descending {
+            true => self.sort_value.cmp(&other.sort_value),
+            false => other.sort_value.cmp(&self.sort_value)
+        };
+        sort_order
+            .then_with(|| self.record.weight.cmp(&other.record.weight))
+            .then_with(|| other.tie_value.cmp(&self.tie_value))
+            .then_with(|| other.record.doc_id.cmp(&self.record.doc_id))
+    }
+}
+
+impl PartialOrd for RankedResult {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Only documents accepted by the filter are considered, so filtering narrows the top results instead of removing from them.
+// The heap keeps every result up to the end of the requested page, and the number of accepted documents is returned as the total hits.
+// Sorting by metadata uses the same heap, so only the top results are ever ordered rather than every match
+fn get_sorted_results(query_ht: HashTable<usize>, num_results: usize, offset: usize, accept: impl Fn(usize) -> bool, rank: impl Fn(PostRecord) -> RankedResult) -> (Vec<PostRecord>, usize) {
+    let mut heap: BinaryHeap<Reverse<RankedResult>> = BinaryHeap::new();
+    let mut total_hits = 0;
+    let num_results = offset.saturating_add(num_results);
+    for entry in query_ht.iter() {
+        let doc_id = entry.key.parse().unwrap();
+        if !accept(doc_id) { continue }
+        total_hits += 1;
+        let result = rank(PostRecord { doc_id, weight: entry.value });
+        if heap.len() < num_results {
+            heap.push(Reverse(result));
+        }
+        else if let Some(Reverse(heap_head)) = heap.peek() {
+            if *heap_head < result {
+                heap.pop();
+                heap.push(Reverse(result));
             }
         }
     }
     let rev_sorted = heap.into_sorted_vec();
     let mut sorted = vec![];
-    for Reverse(elem) in rev_sorted { sorted.push(elem); }
-    sorted
+    for Reverse(elem) in rev_sorted.into_iter().skip(offset) { sorted.push(elem.record); }
+    (sorted, total_hits)
 }
 
 fn get_named_results(filedir: &str, results: Vec<PostRecord>, sizes: &FileSizes) -> Result<Vec<NamedResult>, Error> {
@@ -114,25 +238,273 @@ fn get_named_results(filedir: &str, results: Vec<PostRecord>, sizes: &FileSizes)
     let file = File::open(format!("{filedir}/map"))?;
     let mut reader = BufReader::new(file);
     for result in results {
-        named_results.push(NamedResult { name: get_doc_name(&mut reader, result.doc_id, sizes)?, weight: result.weight })
+        let record = get_map_record(&mut reader, result.doc_id, sizes)?;
+        named_results.push(NamedResult {
+            doc_id: result.doc_id,
+            name: record.file_name,
+            weight: result.weight,
+            snippet: None,
+            size: record.size,
+            modified: record.modified,
+            content_type: record.content_type,
+            encoding: record.encoding,
+            title: record.title,
+            num_tokens: record.num_tokens,
+            explanation: None
+        })
     }
     Ok(named_results)
 }
 
-fn get_doc_name(reader: &mut BufReader<File>, doc_id: usize, sizes: &FileSizes) -> Result<String, Error> {
-    let mut name = String::new();
-    reader.seek(SeekFrom::Start((doc_id * sizes.get_map_record_size()).try_into().unwrap()))?;
-    reader.read_line(&mut name)?;
-    Ok(name.trim().to_string())
+// Snippets are best-effort: without a document store, a document that has moved or been deleted since indexing just gets none.
+// Only the start of each document is read, so a passage past MAX_SNIPPET_SOURCE_BYTES is never picked
+fn get_file_snippet(metadata: &IndexMetadata, result: &NamedResult, tokens: &[String]) -> Option<Snippet> {
+    let indir = metadata.indir.as_ref()?;
+    let contents = read_document_head(&format!("{indir}/{}", result.name), MAX_SNIPPET_SOURCE_BYTES).ok()?;
+    make_snippet(&contents, tokens)
 }
 
-pub fn make_query(query: &str, filedir: &str, num_results: usize) -> Result<Vec<NamedResult>, Error> {
+fn add_snippets(filedir: &str, named_results: &mut [NamedResult], tokens: &[String], sizes: &FileSizes) -> Result<(), Error> {
+    let metadata = get_metadata(filedir)?;
+    if !metadata.doc_store {
+        for result in named_results {
+            result.snippet = get_file_snippet(&metadata, result, tokens);
+        }
+        return Ok(())
+    }
+    // Results are visited in doc id order, so each block of the store is only decoded once however many of them it holds
+    let mut store = StoreReader::open(filedir)?;
+    let mut order: Vec<usize> = (0..named_results.len()).collect();
+    order.sort_by_key(|index| named_results[*index].doc_id);
+    for index in order {
+        let result = &mut named_results[index];
+        let stored_doc = store.get_doc(result.doc_id, sizes)?;
+        result.snippet = stored_doc.and_then(|doc| make_snippet(truncate_text(&doc.text, MAX_SNIPPET_SOURCE_BYTES), tokens));
+    }
+    Ok(())
+}
+
+// Reads documents from the store, keeping the last block decoded so documents next to each other don't decode it again
+struct StoreReader {
+    index_reader: BufReader<File>,
+    store_file: File,
+    block: Option<(usize, Vec<StoredDoc>)>
+}
+
+impl StoreReader {
+    fn open(filedir: &str) -> Result<Self, Error> {
+        let index_reader = BufReader::new(File::open(format!("{filedir}/store_index"))?);
+        let store_file = File::open(format!("{filedir}/store"))?;
+        Ok(Self { index_reader, store_file, block: None })
+    }
+
+    fn read_block(&mut self, block: usize, sizes: &FileSizes) -> Result<Vec<StoredDoc>, Error> {
+        self.index_reader.seek(SeekFrom::Start((block * sizes.get_store_index_record_size()) as u64))?;
+        let mut record_str = String::new();
+        self.index_reader.read_line(&mut record_str)?;
+        let mut split_record = record_str.split_whitespace();
+        let offset: u64 = parse_field(split_record.next(), "store_index")?;
+        let length: u64 = parse_field(split_record.next(), "store_index")?;
+        self.store_file.seek(SeekFrom::Start(offset))?;
+        let decoder = DeflateDecoder::new((&mut self.store_file).take(length));
+        let docs: Vec<StoredDoc> = serde_json::from_reader(decoder)?;
+        Ok(docs)
+    }
+
+    fn get_doc(&mut self, doc_id: usize, sizes: &FileSizes) -> Result<Option<&StoredDoc>, Error> {
+        let block = doc_id / DOCS_PER_STORE_BLOCK;
+        if self.block.as_ref().is_none_or(|(cached, _)| *cached != block) {
+            self.block = Some((block, self.read_block(block, sizes)?));
+        }
+        Ok(self.block.as_ref().and_then(|(_, docs)| docs.get(doc_id % DOCS_PER_STORE_BLOCK)))
+    }
+}
+
+pub fn get_stored_doc(filedir: &str, doc_id: usize) -> Result<Option<StoredDoc>, Error> {
     let sizes = get_sizes(filedir)?;
-    let tokens = get_query_tokens(query);
-    let dict_records = get_dict_records(filedir, &tokens, &sizes)?;
-    let expected_docs = dict_records.iter().fold(0, |sum, record| sum + record.num_docs);
-    let post_records = get_all_post_records(filedir, &dict_records, &sizes)?;
-    let query_ht = make_query_ht(&post_records, expected_docs);
-    let sorted_results = get_sorted_results(query_ht, num_results);
-    get_named_results(filedir, sorted_results, &sizes)
-}
\ No newline at end of file
+    if !get_metadata(filedir)?.doc_store { return Ok(None) }
+    let mut docs = StoreReader::open(filedir)?.read_block(doc_id / DOCS_PER_STORE_BLOCK, &sizes)?;
+    let position = doc_id % DOCS_PER_STORE_BLOCK;
+    if position >= docs.len() { return Ok(None) }
+    Ok(Some(docs.swap_remove(position)))
+}
+
+// The parsed map of the last index queried, which is kept until that index is rebuilt
+struct CachedMap {
+    filedir: String,
+    generation: u64,
+    records: Arc<Vec<MapRecord>>
+}
+
+lazy_static! {
+    static ref MAP_CACHE: Mutex<Option<CachedMap>> = Mutex::new(None);
+}
+
+// Queries that filter, sort or count facets need every document's map record, so the map is only read once per index generation
+fn get_cached_map_records(filedir: &str, metadata: &IndexMetadata, sizes: &FileSizes) -> Result<Arc<Vec<MapRecord>>, Error> {
+    let generation = get_generation(filedir, metadata)?;
+    let mut cache = MAP_CACHE.lock().unwrap();
+    if let Some(cached) = cache.as_ref().filter(|cached| cached.filedir == filedir && cached.generation == generation) {
+        return Ok(Arc::clone(&cached.records))
+    }
+    let records = Arc::new(get_all_map_records(filedir, sizes)?);
+    *cache = Some(CachedMap { filedir: filedir.to_string(), generation, records: Arc::clone(&records) });
+    Ok(records)
+}
+
+fn get_all_map_records(filedir: &str, sizes: &FileSizes) -> Result<Vec<MapRecord>, Error> {
+    let file = File::open(format!("{filedir}/map"))?;
+    let reader = BufReader::new(file);
+    let mut records = vec![];
+    for (doc_id, line) in reader.lines().enumerate() {
+        records.push(parse_map_record(&line?, doc_id, sizes)?);
+    }
+    Ok(records)
+}
+
+fn get_map_record(reader: &mut BufReader<File>, doc_id: usize, sizes: &FileSizes) -> Result<MapRecord, Error> {
+    let mut record_str = String::new();
+    reader.seek(SeekFrom::Start((doc_id * sizes.get_map_record_size()) as u64))?;
+    reader.read_line(&mut record_str)?;
+    parse_map_record(&record_str, doc_id, sizes)
+}
+
+fn parse_map_record(record_str: &str, doc_id: usize, sizes: &FileSizes) -> Result<MapRecord, Error> {
+    let mut fields = MapFields { line: record_str, offset: 0 };
+    let name = fields.next(sizes.map_name_length)?.to_string();
+    if !sizes.has_map_metadata() { return Ok(MapRecord::new(doc_id, name, 0, 0)) }
+    let size = parse_field(Some(fields.next(sizes.map_size_length)?), "map")?;
+    let modified = parse_field(Some(fields.next(sizes.map_modified_length)?), "map")?;
+    let mut record = MapRecord::new(doc_id, name, size, modified);
+    record.content_type = fields.next(sizes.map_content_type_length)?.to_string();
+    record.encoding = fields.next(sizes.map_encoding_length)?.to_string();
+    let title = fields.next(sizes.map_title_length)?;
+    record.title = if title.is_empty() { None } else { Some(title.to_string()) };
+    if sizes.has_map_tokens() {
+        record.num_tokens = parse_field(Some(fields.next(sizes.map_tokens_length)?), "map")?;
+    }
+    Ok(record)
+}
+
+// Splits a map line into its fixed-length, space separated fields
+struct MapFields<'a> {
+    line: &'a str,
+    offset: usize
+}
+
+impl<'a> MapFields<'a> {
+    fn next(&mut self, length: usize) -> Result<&'a str, Error> {
+        let field = self.line.get(self.offset..self.offset + length)
+            .ok_or_else(|| Error::CorruptIndex(format!("map record too short for field at {}", self.offset)))?;
+        self.offset += length + 1;
+        Ok(field.trim())
+    }
+}
+
+pub fn make_query(query: &str, filedir: &str, options: &QueryOptions) -> Result<QueryResults, Error> {
+    let sizes = get_sizes(filedir)?;
+    let metadata = get_metadata(filedir)?;
+    let parsed_query = parse_query(query)?;
+    let terms = get_query_terms(&parsed_query);
+    let tokens: Vec<String> = terms.iter().map(|term| term.token.clone()).collect();
+    let dict_records = get_dict_records(filedir, &terms, &metadata, &sizes)?;
+    let needs_map = options.facets || !parsed_query.filters.is_empty() || options.tie_break != TieBreak::DocId
+        || options.sort.field != SortField::Relevance;
+    let map_records = if needs_map { get_cached_map_records(filedir, &metadata, &sizes)? } else { Arc::default() };
+    let accept_filters = |doc_id: usize| parsed_query.filters.is_empty() || map_records.get(doc_id).is_some_and(|record| parsed_query.matches(record));
+    // A required term that isn't in the index can't be matched by any document
+    let num_required = terms.iter().filter(|term| term.required).count();
+    let required_records: Vec<(DictRecord, QueryTerm)> = dict_records.iter().filter(|(_, term)| term.required).cloned().collect();
+    if required_records.len() < num_required {
+        let facets = options.facets.then(|| count_facets(&[], &map_records));
+        return Ok(QueryResults { results: vec![], total_hits: 0, total_hits_exact: true, offset: options.offset, facets })
+    }
+    let required_docs = match num_required {
+        0 => None,
+        _ => Some(intersect(&mut open_cursors(filedir, &required_records, &metadata, &sizes)?)?)
+    };
+    if can_use_top_k(options) {
+        let num_results = options.offset.saturating_add(options.num_results);
+        let cursors = open_cursors(filedir, &dict_records, &metadata, &sizes)?;
+        let top_k = match &required_docs {
+            Some(docs) => Some(required_top_k(cursors, docs, num_results, accept_filters)?),
+            None if metadata.postings_order == PostingsOrder::Impact && can_use_impact_order(&dict_records) => {
+                Some(impact_top_k(filedir, &dict_records, &sizes, num_results, accept_filters)?)
+            }
+            None if has_max_weights(&dict_records) => Some(max_score_top_k(cursors, num_results, accept_filters)?),
+            None => None
+        };
+        // Every posting of a lone unfiltered term is a hit, so its document count is the exact total however little was read
+        let top_k = top_k.map(|(sorted_results, total_hits, total_hits_exact)| match dict_records.as_slice() {
+            [(record, _)] if parsed_query.filters.is_empty() => (sorted_results, record.num_docs, true),
+            _ => (sorted_results, total_hits, total_hits_exact)
+        });
+        // Callers that need the exact total fall back to visiting every match when the top k stopped early
+        if let Some((mut sorted_results, total_hits, total_hits_exact)) = top_k.filter(|(_, _, total_hits_exact)| *total_hits_exact || !options.exact_hits) {
+            sorted_results.drain(..options.offset.min(sorted_results.len()));
+            let named_results = finish_results(filedir, sorted_results, &tokens, &dict_records, &sizes, options)?;
+            return Ok(QueryResults { results: named_results, total_hits, total_hits_exact, offset: options.offset, facets: None })
+        }
+    }
+    let accept = |doc_id| accept_filters(doc_id) && required_docs.as_ref().is_none_or(|docs| docs.binary_search(&doc_id).is_ok());
+    let expected_docs = dict_records.iter().fold(0, |sum, (record, _)| sum + record.num_docs);
+    let term_postings = get_all_post_records(filedir, &dict_records, &sizes)?;
+    let query_ht = make_query_ht(&term_postings, expected_docs);
+    let facets = match options.facets {
+        true => {
+            let matching: Vec<usize> = query_ht.iter()
+                .map(|entry| entry.key.parse().unwrap())
+                .filter(|doc_id| accept(*doc_id))
+                .collect();
+            Some(count_facets(&matching, &map_records))
+        }
+        false => None
+    };
+    let Sort { field, descending } = options.sort;
+    let rank = |record: PostRecord| RankedResult {
+        sort_value: SortValue::from_sort(field, record.weight, map_records.get(record.doc_id)),
+        descending,
+        tie_value: SortValue::from_tie_break(options.tie_break, map_records.get(record.doc_id)),
+        record
+    };
+    let (sorted_results, total_hits) = get_sorted_results(query_ht, options.num_results, options.offset, accept, rank);
+    let named_results = finish_results(filedir, sorted_results, &tokens, &dict_records, &sizes, options)?;
+    Ok(QueryResults { results: named_results, total_hits, total_hits_exact: true, offset: options.offset, facets })
+}
+
+// The top-k evaluators only find the top results by weight, so they can't be used when other documents need to be
+// looked at or compared differently
+fn can_use_top_k(options: &QueryOptions) -> bool {
+    options.sort.field == SortField::Relevance && options.tie_break == TieBreak::DocId && !options.facets
+}
+
+fn has_max_weights(dict_records: &[(DictRecord, QueryTerm)]) -> bool {
+    dict_records.iter().all(|(record, _)| record.max_weight.is_some())
+}
+
+// Lists are read lazily when the index has them sorted by doc id, otherwise each one is read whole and sorted in memory
+fn open_cursors(filedir: &str, dict_records: &[(DictRecord, QueryTerm)], metadata: &IndexMetadata, sizes: &FileSizes) -> Result<Vec<PostingCursor>, Error> {
+    let mut cursors = vec![];
+    for (record, term) in dict_records {
+        let cursor = match metadata.postings_order {
+            PostingsOrder::DocId => PostingCursor::open(filedir, record, term.boost, sizes)?,
+            PostingsOrder::Unsorted | PostingsOrder::Impact => {
+                let file = File::open(format!("{filedir}/post"))?;
+                let postings = get_term_post_records(&mut BufReader::new(file), record, term.boost, sizes)?;
+                PostingCursor::from_postings(postings, boost_weight(record.max_weight.unwrap_or(0), term.boost))
+            }
+        };
+        cursors.push(cursor);
+    }
+    Ok(cursors)
+}
+
+fn finish_results(filedir: &str, sorted_results: Vec<PostRecord>, tokens: &[String], dict_records: &[(DictRecord, QueryTerm)], sizes: &FileSizes, options: &QueryOptions) -> Result<Vec<NamedResult>, Error> {
+    let mut named_results = get_named_results(filedir, sorted_results, sizes)?;
+    add_snippets(filedir, &mut named_results, tokens, sizes)?;
+    if options.explain {
+        let term_postings = get_all_post_records(filedir, dict_records, sizes)?;
+        add_explanations(&mut named_results, dict_records, &term_postings, get_num_docs(filedir, sizes)?);
+    }
+    Ok(named_results)
+}
@@ -0,0 +1,96 @@
+use std::{cmp::Reverse, collections::BinaryHeap, fs::{File, OpenOptions}, io::{BufRead, BufReader, BufWriter, Write}};
+
+use crate::data_models::{DocFrequency, GlobHTBucket};
+use crate::error::{Error, parse_field};
+use crate::hashtable::{HashTable, TableEntry};
+
+// Runs are the partial indexes written when the tokenizer's memory budget is hit, which are merged back together
+// when the index is written. Each line is one posting, "doc_id raw_term_frequency relative_term_frequency term",
+// sorted by term and then doc id. The term is last and runs to the end of the line, since the parser can produce empty ones
+pub fn write_run(path: &str, glob_ht: &HashTable<GlobHTBucket>) -> Result<(), Error> {
+    let run_file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+    let mut writer = BufWriter::new(run_file);
+    let mut terms: Vec<&TableEntry<GlobHTBucket>> = glob_ht.iter().collect();
+    terms.sort_by(|a, b| a.key.cmp(&b.key));
+    for entry in terms {
+        let mut files: Vec<&DocFrequency> = entry.value.get_files().iter().collect();
+        files.sort_by_key(|file| file.doc_id);
+        for file in files {
+            writeln!(writer, "{} {} {} {}", file.doc_id, file.raw_term_frequency, file.relative_term_frequency, entry.key)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+struct RunReader {
+    reader: BufReader<File>,
+    next: Option<(String, DocFrequency)>
+}
+
+impl RunReader {
+    fn open(path: &str) -> Result<Self, Error> {
+        let mut run = Self { reader: BufReader::new(File::open(path)?), next: None };
+        run.advance()?;
+        Ok(run)
+    }
+
+    fn advance(&mut self) -> Result<(), Error> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            self.next = None;
+            return Ok(())
+        }
+        let mut fields = line.strip_suffix('\n').unwrap_or(&line).splitn(4, ' ');
+        let file = DocFrequency {
+            doc_id: parse_field(fields.next(), "run")?,
+            raw_term_frequency: parse_field(fields.next(), "run")?,
+            relative_term_frequency: parse_field(fields.next(), "run")?
+        };
+        let term = fields.next().ok_or(Error::CorruptIndex("invalid record in run".to_string()))?.to_string();
+        self.next = Some((term, file));
+        Ok(())
+    }
+}
+
+// Reads the postings of several runs back one term at a time, in term order and with each term's postings in doc id order
+pub struct RunMerger {
+    runs: Vec<RunReader>,
+    heap: BinaryHeap<Reverse<(String, usize, usize)>>
+}
+
+impl RunMerger {
+    pub fn open(paths: &[String]) -> Result<Self, Error> {
+        let mut merger = Self { runs: vec![], heap: BinaryHeap::new() };
+        for path in paths {
+            let run = RunReader::open(path)?;
+            if let Some((term, file)) = &run.next {
+                merger.heap.push(Reverse((term.clone(), file.doc_id, merger.runs.len())));
+            }
+            merger.runs.push(run);
+        }
+        Ok(merger)
+    }
+
+    fn pop_posting(&mut self) -> Result<Option<(String, DocFrequency)>, Error> {
+        let Some(Reverse((_, _, run_num))) = self.heap.pop() else { return Ok(None) };
+        let run = &mut self.runs[run_num];
+        let posting = run.next.take();
+        run.advance()?;
+        if let Some((term, file)) = &run.next {
+            self.heap.push(Reverse((term.clone(), file.doc_id, run_num)));
+        }
+        Ok(posting)
+    }
+
+    pub fn next_term(&mut self) -> Result<Option<TableEntry<GlobHTBucket>>, Error> {
+        let Some((term, file)) = self.pop_posting()? else { return Ok(None) };
+        let mut files = vec![file];
+        while self.heap.peek().is_some_and(|Reverse((next_term, _, _))| *next_term == term) {
+            if let Some((_, file)) = self.pop_posting()? {
+                files.push(file);
+            }
+        }
+        Ok(Some(TableEntry { key: term, value: GlobHTBucket::from_files(files) }))
+    }
+}
@@ -0,0 +1,62 @@
+use std::{ops::AddAssign, sync::Mutex, mem::replace};
+
+use crate::constants::MAX_LOAD_FACTOR;
+use crate::hashtable::{HashTable, shard_index};
+
+// A table split into separately locked shards, so several threads can insert at once.
+// Each key only ever goes in one shard, so merging them at the end never has to combine values
+pub struct ShardedHashTable<T> {
+    shards: Vec<Mutex<HashTable<T>>>,
+    shard_size: usize
+}
+
+impl<T> ShardedHashTable<T>
+where T: AddAssign
+{
+    pub fn new(size: usize, num_shards: usize) -> Self {
+        let num_shards = num_shards.max(1);
+        let shard_size = size / num_shards;
+        Self {
+            shards: (0..num_shards).map(|_| Mutex::new(HashTable::new(shard_size))).collect(),
+            shard_size
+        }
+    }
+
+    // Sorts the entries by shard first, so each shard is locked once per batch rather than once per entry
+    pub fn insert_combine_batch<K: AsRef<str>>(&self, entries: Vec<(K, T)>) {
+        let mut entries: Vec<(usize, K, T)> = entries.into_iter()
+            .map(|(key, value)| (shard_index(key.as_ref(), self.shards.len()), key, value))
+            .collect();
+        entries.sort_unstable_by_key(|(shard_num, _, _)| *shard_num);
+        let mut entries = entries.into_iter().peekable();
+        while let Some((shard_num, key, value)) = entries.next() {
+            let mut shard = self.shards[shard_num].lock().unwrap();
+            shard.insert_combine(key.as_ref(), value);
+            while let Some((_, key, value)) = entries.next_if(|(next_shard_num, _, _)| *next_shard_num == shard_num) {
+                shard.insert_combine(key.as_ref(), value);
+            }
+        }
+    }
+
+    // Merges the shards into one table with at least the given number of slots
+    pub fn into_table(self, size: usize) -> HashTable<T> {
+        let shards: Vec<HashTable<T>> = self.shards.into_iter().map(|shard| shard.into_inner().unwrap()).collect();
+        merge_shards(shards, size)
+    }
+
+    // Like into_table, but leaves the shards empty so other threads can keep inserting.
+    // Each shard is emptied as it's locked, so a batch being inserted at the same time may be split between the two tables
+    pub fn take_table(&self, size: usize) -> HashTable<T> {
+        let shards: Vec<HashTable<T>> = self.shards.iter().map(|shard| replace(&mut *shard.lock().unwrap(), HashTable::new(self.shard_size))).collect();
+        merge_shards(shards, size)
+    }
+}
+
+fn merge_shards<T: AddAssign>(shards: Vec<HashTable<T>>, size: usize) -> HashTable<T> {
+    let num_elements: usize = shards.iter().map(HashTable::len).sum();
+    let mut table = HashTable::new(size.max((num_elements as f64 / MAX_LOAD_FACTOR) as usize + 1));
+    for entry in shards.into_iter().flatten() {
+        table.insert_combine(&entry.key, entry.value);
+    }
+    table
+}
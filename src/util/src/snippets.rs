@@ -0,0 +1,71 @@
+use std::ops::Range;
+
+use regex::Regex;
+use lazy_static::lazy_static;
+
+use crate::constants::*;
+use crate::data_models::{Snippet, SnippetFragment};
+use crate::hashtable::HashTable;
+use crate::parser::parse_with_spans;
+
+lazy_static! {
+    static ref RE_CLEAN_TAGS: Regex = Regex::new(r"<[^>]*>").unwrap();
+    static ref RE_CLEAN_WHITESPACE: Regex = Regex::new(r"\s+").unwrap();
+}
+
+// Tags between tokens become spaces, while tags inside a token (like "<b>E</b>lephants") are removed entirely
+fn clean_snippet_text(text: &str, tag_replacement: &str) -> String {
+    let without_tags = RE_CLEAN_TAGS.replace_all(text, tag_replacement);
+    RE_CLEAN_WHITESPACE.replace_all(&without_tags, " ").to_string()
+}
+
+// Picks the window of SNIPPET_LENGTH tokens that contains the most distinct query terms,
+// using the total number of matches to break ties
+fn find_best_window(tokens: &[(String, Range<usize>)], query_ht: &HashTable<usize>) -> usize {
+    let mut best_start = 0;
+    let mut best_score = (0, 0);
+    for start in 0..tokens.len().saturating_sub(SNIPPET_LENGTH) + 1 {
+        let end = (start + SNIPPET_LENGTH).min(tokens.len());
+        let mut seen: Vec<&str> = vec![];
+        let mut total_matches = 0;
+        for (token, _) in &tokens[start..end] {
+            if query_ht.intable(token) {
+                total_matches += 1;
+                if !seen.contains(&token.as_str()) { seen.push(token) }
+            }
+        }
+        if (seen.len(), total_matches) > best_score {
+            best_score = (seen.len(), total_matches);
+            best_start = start;
+        }
+    }
+    best_start
+}
+
+fn push_fragment(fragments: &mut Vec<SnippetFragment>, text: String, matched: bool) {
+    match fragments.last_mut() {
+        Some(last) if last.matched == matched => last.text.push_str(&text),
+        _ => fragments.push(SnippetFragment { text, matched })
+    }
+}
+
+pub fn make_snippet(text: &str, query_tokens: &[String]) -> Option<Snippet> {
+    let tokens = parse_with_spans(text);
+    if tokens.is_empty() { return None }
+    let mut query_ht: HashTable<usize> = HashTable::new(query_tokens.len() * 3 + 1);
+    for token in query_tokens {
+        query_ht.insert_combine(token, 1);
+    }
+    let start = find_best_window(&tokens, &query_ht);
+    let end = (start + SNIPPET_LENGTH).min(tokens.len());
+    let mut fragments = vec![];
+    if start > 0 { push_fragment(&mut fragments, "... ".to_string(), false) }
+    let mut previous_end = tokens[start].1.start;
+    for (token, span) in &tokens[start..end] {
+        push_fragment(&mut fragments, clean_snippet_text(&text[previous_end..span.start], " "), false);
+        push_fragment(&mut fragments, clean_snippet_text(&text[span.clone()], ""), query_ht.intable(token));
+        previous_end = span.end;
+    }
+    if end < tokens.len() { push_fragment(&mut fragments, " ...".to_string(), false) }
+    Some(Snippet { fragments })
+}
@@ -0,0 +1,73 @@
+use std::{fs::{self, File, OpenOptions}, io::{BufWriter, Read, Seek, SeekFrom, Write}, sync::Mutex};
+
+use crate::error::Error;
+use crate::hashtable::HashTable;
+use crate::constants::{GLOB_HT_SIZE, MAX_STORED_TEXT_BYTES};
+use crate::documents::truncate_text;
+
+// The text of each document as the tokenizer read it, appended by the tokenizing threads in whatever order they finish,
+// so the document store can be written afterwards without reading indir again. Texts are found by file name, and only the
+// first MAX_STORED_TEXT_BYTES of each are kept
+pub struct StoreSpool {
+    path: String,
+    spool: Mutex<SpoolWriter>
+}
+
+struct SpoolWriter {
+    writer: BufWriter<File>,
+    length: u64,
+    texts: HashTable<(u64, usize)>
+}
+
+impl StoreSpool {
+    pub fn create(path: &str) -> Result<Self, Error> {
+        let spool_file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+        let spool = SpoolWriter { writer: BufWriter::new(spool_file), length: 0, texts: HashTable::new(GLOB_HT_SIZE) };
+        Ok(Self { path: path.to_string(), spool: Mutex::new(spool) })
+    }
+
+    pub fn add(&self, file_name: &str, text: &str) -> Result<(), Error> {
+        let text = truncate_text(text, MAX_STORED_TEXT_BYTES);
+        let mut spool = self.spool.lock().unwrap();
+        spool.writer.write_all(text.as_bytes())?;
+        let offset = spool.length;
+        spool.texts.insert(file_name, (offset, text.len()));
+        spool.length += text.len() as u64;
+        Ok(())
+    }
+
+    pub fn into_reader(self) -> Result<StoreSpoolReader, Error> {
+        let mut spool = self.spool.into_inner().unwrap();
+        spool.writer.flush()?;
+        Ok(StoreSpoolReader { file: File::open(&self.path)?, path: self.path, texts: spool.texts })
+    }
+
+    pub fn remove(self) -> Result<(), Error> {
+        drop(self.spool);
+        fs::remove_file(self.path)?;
+        Ok(())
+    }
+}
+
+pub struct StoreSpoolReader {
+    path: String,
+    file: File,
+    texts: HashTable<(u64, usize)>
+}
+
+impl StoreSpoolReader {
+    pub fn read_text(&mut self, file_name: &str) -> Result<String, Error> {
+        let (offset, length) = *self.texts.get(file_name)
+            .ok_or_else(|| Error::CorruptIndex(format!("no stored text for {file_name}")))?;
+        let mut text = vec![0; length];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut text)?;
+        String::from_utf8(text).map_err(|_| Error::CorruptIndex(format!("stored text for {file_name} isn't utf-8")))
+    }
+
+    pub fn remove(self) -> Result<(), Error> {
+        drop(self.file);
+        fs::remove_file(self.path)?;
+        Ok(())
+    }
+}
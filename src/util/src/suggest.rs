@@ -0,0 +1,110 @@
+use std::cmp::Reverse;
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom, BufRead};
+
+use crate::parser::parse;
+use crate::data_models::{FileSizes, Suggestion};
+use crate::error::{Error, parse_field};
+use crate::hashtable::HashTable;
+use crate::read_query_files::get_sizes;
+use crate::constants::MAX_SUGGEST_SCAN_LINES;
+
+fn read_one_term_line(reader: &mut BufReader<File>, sizes: &FileSizes, line: usize) -> Result<(String, usize), Error> {
+    reader.seek(SeekFrom::Start((line * sizes.get_term_record_size()) as u64))?;
+    read_next_term_line(reader)
+}
+
+fn read_next_term_line(reader: &mut BufReader<File>) -> Result<(String, usize), Error> {
+    let mut record_str = String::new();
+    reader.read_line(&mut record_str)?;
+    let mut split_record = record_str.split_whitespace();
+    let term = parse_field(split_record.next(), "terms")?;
+    let num_docs = parse_field(split_record.next(), "terms")?;
+    Ok((term, num_docs))
+}
+
+// Terms are written in sorted order, so the first candidate is found with a binary search
+// over the fixed-length lines and every completion follows it contiguously
+fn find_first_term_line(reader: &mut BufReader<File>, sizes: &FileSizes, prefix: &str) -> Result<usize, Error> {
+    let (mut low, mut high) = (0, sizes.num_term_lines);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let (term, _) = read_one_term_line(reader, sizes, mid)?;
+        if term.as_str() < prefix { low = mid + 1 }
+        else { high = mid }
+    }
+    Ok(low)
+}
+
+// Every completion that could still make the top num_results once popularity breaks ties between equal document counts.
+// Reading these needs no popularity, so callers can look it up afterwards for just these terms
+fn get_completions(filedir: &str, prefix: &str, num_results: usize, sizes: &FileSizes) -> Result<Vec<Suggestion>, Error> {
+    if num_results == 0 { return Ok(vec![]) }
+    let file = File::open(format!("{filedir}/terms"))?;
+    let mut reader = BufReader::new(file);
+    let first_line = find_first_term_line(&mut reader, sizes, prefix)?;
+    // The completions are read in order from the first one rather than seeking to each, and short prefixes with
+    // more than MAX_SUGGEST_SCAN_LINES completions only rank the first of them
+    reader.seek(SeekFrom::Start((first_line * sizes.get_term_record_size()) as u64))?;
+    let last_line = sizes.num_term_lines.min(first_line + MAX_SUGGEST_SCAN_LINES);
+    let mut completions = vec![];
+    for _ in first_line..last_line {
+        let (term, num_docs) = read_next_term_line(&mut reader)?;
+        if !term.starts_with(prefix) { break }
+        completions.push(Suggestion { term, num_docs, popularity: 0 });
+    }
+    completions.sort_by_key(|completion| Reverse(completion.num_docs));
+    if let Some(min_docs) = completions.get(num_results - 1).map(|completion| completion.num_docs) {
+        completions.retain(|completion| completion.num_docs >= min_docs);
+    }
+    Ok(completions)
+}
+
+// The completions of a prefix's last word, read from the index but not yet ranked
+pub struct SuggestionCandidates {
+    context: Vec<String>,
+    completions: Vec<Suggestion>,
+    num_results: usize
+}
+
+impl SuggestionCandidates {
+    // Only looks each candidate up, so a shared table of search counts is held as briefly as possible
+    pub fn with_popularity(mut self, popularity: &HashTable<usize>) -> Self {
+        for completion in &mut self.completions {
+            completion.popularity = popularity.get(&completion.term).copied().unwrap_or(0);
+        }
+        self
+    }
+
+    // Ranked by document count, then popularity, then alphabetically
+    pub fn rank(mut self) -> Vec<Suggestion> {
+        self.completions.sort_by(|a, b| (b.num_docs, b.popularity, &a.term).cmp(&(a.num_docs, a.popularity, &b.term)));
+        self.completions.truncate(self.num_results);
+        // Earlier words in the prefix are kept so the frontend can show the whole completed query
+        if !self.context.is_empty() {
+            let context = self.context.join(" ");
+            for suggestion in &mut self.completions {
+                suggestion.term = format!("{context} {}", suggestion.term);
+            }
+        }
+        self.completions
+    }
+}
+
+pub fn find_suggestion_candidates(prefix: &str, filedir: &str, num_results: usize) -> Result<SuggestionCandidates, Error> {
+    let sizes = get_sizes(filedir)?;
+    let mut context = parse(prefix);
+    let completions = match context.pop() {
+        Some(last_token) => get_completions(filedir, &last_token, num_results, &sizes)?,
+        None => vec![]
+    };
+    Ok(SuggestionCandidates { context, completions, num_results })
+}
+
+pub fn make_suggestions(prefix: &str, filedir: &str, num_results: usize, popularity: Option<&HashTable<usize>>) -> Result<Vec<Suggestion>, Error> {
+    let candidates = find_suggestion_candidates(prefix, filedir, num_results)?;
+    Ok(match popularity {
+        Some(popularity) => candidates.with_popularity(popularity).rank(),
+        None => candidates.rank()
+    })
+}
@@ -1,61 +1,139 @@
-use std::{fs::{File, OpenOptions}, io::{Error, Write, BufWriter}};
+use std::{cmp::Reverse, fs::{self, File, OpenOptions}, io::{Write, BufWriter}};
 
-use crate::{data_models::{GlobHTBucket, DocFrequency, MapRecord, FileSizes}, hashtable::{TableEntry, HashTable}};
+use flate2::{write::DeflateEncoder, Compression};
+
+use crate::{data_models::{GlobHTBucket, DocFrequency, MapRecord, DocStatus, FileSizes, IndexMetadata, IndexStats, PostingsOrder, PruningPolicy, StoredDoc}, hashtable::{HashTable, Slot}};
+use crate::store_spool::{StoreSpool, StoreSpoolReader};
+use crate::runs::RunMerger;
+use crate::error::Error;
 use crate::constants::*;
 
-fn write_dict(outdir: &str, glob_ht: &HashTable<GlobHTBucket>, sizes: &FileSizes) -> Result<(), Error> {
+// What the dict holds for a term once its postings have been written
+#[derive(Default)]
+struct DictTerm {
+    num_docs: usize,
+    post_line_start: usize,
+    max_weight: usize
+}
+
+impl DictTerm {
+    fn new(bucket: &GlobHTBucket, post_line_start: usize, total_docs: usize) -> Self {
+        Self { num_docs: bucket.get_num_docs(), post_line_start, max_weight: get_max_weight(bucket, total_docs) }
+    }
+}
+
+// Terms the pruning policy leaves out are written as !DELETED, and their postings are left out of the post file
+fn write_dict(outdir: &str, glob_ht: &HashTable<GlobHTBucket>, sizes: &FileSizes, total_docs: usize, pruning: &PruningPolicy) -> Result<(), Error> {
     let dict_file = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{outdir}/dict"))?;
     let mut writer = BufWriter::new(dict_file);
     let mut count: usize = 0;
-    for bucket in glob_ht.get_buckets() {
-        count = write_dict_line(&mut writer, bucket, &sizes, count)?;
+    for slot in glob_ht.get_slots() {
+        match slot {
+            Slot::Occupied(entry) if pruning.keeps(&entry.key, &entry.value) => {
+                let dict_term = DictTerm::new(&entry.value, count, total_docs);
+                count += dict_term.num_docs;
+                write_dict_line(&mut writer, &entry.key, &dict_term, sizes)?;
+            }
+            Slot::Empty => write_dict_line(&mut writer, "!NULL", &DictTerm::default(), sizes)?,
+            _ => write_dict_line(&mut writer, "!DELETED", &DictTerm::default(), sizes)?
+        }
     }
     Ok(())
 }
 
-fn write_dict_line(writer: &mut BufWriter<File>, bucket: &Option<TableEntry<GlobHTBucket>>, sizes: &FileSizes, count: usize) -> Result<usize, Error> {
-    let mut new_count = count;
-    let (term, num_docs, post_line_start) = match bucket {
-        Some(entry) => {
-            if entry.value.is_rare() {
-                ("!DELETED", 0, 0)
-            } 
-            else {
-                new_count += entry.value.get_num_docs();
-                (entry.key.as_str(), entry.value.get_num_docs(), count)
-            }
+// The dict for an index built from runs, where each term's place in the post file was recorded as it was written
+fn write_dict_terms(outdir: &str, dict_terms: &HashTable<DictTerm>, sizes: &FileSizes) -> Result<(), Error> {
+    let dict_file = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{outdir}/dict"))?;
+    let mut writer = BufWriter::new(dict_file);
+    for slot in dict_terms.get_slots() {
+        match slot {
+            Slot::Occupied(entry) => write_dict_line(&mut writer, &entry.key, &entry.value, sizes)?,
+            Slot::Empty => write_dict_line(&mut writer, "!NULL", &DictTerm::default(), sizes)?,
+            Slot::Deleted => write_dict_line(&mut writer, "!DELETED", &DictTerm::default(), sizes)?
         }
-        None => ("!NULL", 0, 0)
-    };
+    }
+    Ok(())
+}
+
+fn write_dict_line(writer: &mut BufWriter<File>, term: &str, dict_term: &DictTerm, sizes: &FileSizes) -> Result<(), Error> {
     writeln!(writer, 
-            "{:<term_length$.term_length$} {:<num_docs_length$.num_docs_length$} {:<start_length$.start_length$}",
-            term, num_docs.to_string(), post_line_start.to_string(),
+            "{:<term_length$.term_length$} {:<num_docs_length$.num_docs_length$} {:<start_length$.start_length$} {:<max_weight_length$.max_weight_length$}",
+            term, dict_term.num_docs.to_string(), dict_term.post_line_start.to_string(), dict_term.max_weight.to_string(),
             term_length = TERM_LENGTH,
             num_docs_length = sizes.num_docs_length,
-            start_length = sizes.post_line_start_length
+            start_length = sizes.post_line_start_length,
+            max_weight_length = sizes.max_weight_length
     )?;
-    Ok(new_count)
-}
-
-fn write_post(outdir: &str, glob_ht: &HashTable<GlobHTBucket>, sizes: &FileSizes, total_docs: usize) -> Result<(), Error> {
-    let post_file = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{outdir}/post"))?;
-    let mut writer = BufWriter::new(post_file);
-    for bucket in glob_ht.get_buckets() {
-        if let Some(entry) = bucket {
-            if !entry.value.is_rare() {
-                let idf = 1.0 + (total_docs as f64 / entry.value.get_num_docs() as f64).log10();
-                for file in entry.value.get_files() {
-                    write_post_line(&mut writer, file, sizes, idf)?;
-                }
+    Ok(())
+}
+
+fn get_idf(bucket: &GlobHTBucket, total_docs: usize) -> f64 {
+    1.0 + (total_docs as f64 / bucket.get_num_docs() as f64).log10()
+}
+
+fn get_weight(file: &DocFrequency, idf: f64) -> usize {
+    (file.relative_term_frequency * idf * WEIGHT_MULTIPLIER) as usize
+}
+
+// The upper bound on what the term can add to any document's score, which lets queries skip documents that can't reach the top results
+fn get_max_weight(bucket: &GlobHTBucket, total_docs: usize) -> usize {
+    let idf = get_idf(bucket, total_docs);
+    bucket.get_files().iter().map(|file| get_weight(file, idf)).max().unwrap_or(0)
+}
+
+// Each term's postings are written in doc id order, or by descending weight for impact ordered indexes.
+// With doc id order, the skips file holds the doc id at the start of every block of POSTINGS_PER_SKIP post lines,
+// so a query can jump through a list to a doc id without reading the lines before it
+fn write_post(outdir: &str, glob_ht: &HashTable<GlobHTBucket>, doc_ids: &[usize], sizes: &FileSizes, total_docs: usize, metadata: &IndexMetadata) -> Result<(), Error> {
+    let mut post_writer = PostWriter::open(outdir, doc_ids)?;
+    for entry in glob_ht.iter() {
+        if metadata.pruning.keeps(&entry.key, &entry.value) {
+            post_writer.write_postings(&entry.value, sizes, total_docs, metadata.postings_order)?;
+        }
+    }
+    Ok(())
+}
+
+// Writes the post and skips files together, keeping track of the next post line.
+// Postings are written with the doc ids from remove_failed_docs, which keep the same order
+struct PostWriter<'a> {
+    writer: BufWriter<File>,
+    skips_writer: BufWriter<File>,
+    line: usize,
+    doc_ids: &'a [usize]
+}
+
+impl<'a> PostWriter<'a> {
+    fn open(outdir: &str, doc_ids: &'a [usize]) -> Result<Self, Error> {
+        let post_file = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{outdir}/post"))?;
+        let skips_file = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{outdir}/skips"))?;
+        Ok(Self { writer: BufWriter::new(post_file), skips_writer: BufWriter::new(skips_file), line: 0, doc_ids })
+    }
+
+    fn write_postings(&mut self, bucket: &GlobHTBucket, sizes: &FileSizes, total_docs: usize, order: PostingsOrder) -> Result<(), Error> {
+        let idf = get_idf(bucket, total_docs);
+        let mut files: Vec<&DocFrequency> = bucket.get_files().iter().collect();
+        match order {
+            PostingsOrder::Impact => files.sort_by_key(|file| (Reverse(get_weight(file, idf)), file.doc_id)),
+            _ => files.sort_by_key(|file| file.doc_id)
+        }
+        for file in files {
+            if order == PostingsOrder::DocId && self.line.is_multiple_of(POSTINGS_PER_SKIP) {
+                write_skip_line(&mut self.skips_writer, self.doc_ids[file.doc_id], sizes)?;
             }
+            write_post_line(&mut self.writer, self.doc_ids[file.doc_id], get_weight(file, idf), sizes)?;
+            self.line += 1;
         }
+        Ok(())
     }
+}
+
+fn write_skip_line(writer: &mut BufWriter<File>, doc_id: usize, sizes: &FileSizes) -> Result<(), Error> {
+    writeln!(writer, "{:<doc_id_length$.doc_id_length$}", doc_id.to_string(), doc_id_length = sizes.doc_id_length)?;
     Ok(())
 }
 
-fn write_post_line(writer: &mut BufWriter<File>, file: &DocFrequency, sizes: &FileSizes, idf: f64) -> Result<(), Error> {
-    let doc_id = file.doc_id;
-    let weight = (file.relative_term_frequency * idf * WEIGHT_MULTIPLIER) as usize; 
+fn write_post_line(writer: &mut BufWriter<File>, doc_id: usize, weight: usize, sizes: &FileSizes) -> Result<(), Error> {
     writeln!(writer,
         "{:<doc_id_length$.doc_id_length$} {:<weight_length$.weight_length$}",
         doc_id.to_string(), weight.to_string(),
@@ -70,13 +148,91 @@ fn write_map(outdir: &str, docs: &Vec<MapRecord>, sizes: &FileSizes) -> Result<(
     let map_file = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{outdir}/map"))?;
     let mut writer = BufWriter::new(map_file);
     for doc in docs {
-        write_map_line(&mut writer, &doc.file_name, sizes)?;
+        write_map_line(&mut writer, doc, sizes)?;
+    }
+    Ok(())
+}
+
+fn write_map_line(writer: &mut BufWriter<File>, doc: &MapRecord, sizes: &FileSizes) -> Result<(), Error> {
+    writeln!(writer,
+        "{} {} {} {} {} {} {}",
+        pad_field(&doc.file_name, sizes.map_name_length),
+        pad_field(&doc.size.to_string(), sizes.map_size_length),
+        pad_field(&doc.modified.to_string(), sizes.map_modified_length),
+        pad_field(&doc.content_type, sizes.map_content_type_length),
+        pad_field(&doc.encoding, sizes.map_encoding_length),
+        pad_field(doc.title.as_deref().unwrap_or(""), sizes.map_title_length),
+        pad_field(&doc.num_tokens.to_string(), sizes.map_tokens_length)
+    )?;
+    Ok(())
+}
+
+// Map fields are padded by bytes rather than chars, so lines stay a fixed length when names or titles aren't ascii
+fn pad_field(value: &str, length: usize) -> String {
+    let mut end = value.len().min(length);
+    while !value.is_char_boundary(end) { end -= 1 }
+    format!("{}{}", &value[..end], " ".repeat(length - end))
+}
+
+fn write_terms(outdir: &str, mut terms: Vec<(&str, usize)>, sizes: &FileSizes) -> Result<(), Error> {
+    let terms_file = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{outdir}/terms"))?;
+    let mut writer = BufWriter::new(terms_file);
+    terms.sort_by(|a, b| a.0.cmp(b.0));
+    for (term, num_docs) in terms {
+        write_term_line(&mut writer, term, num_docs, sizes)?;
     }
     Ok(())
 }
 
-fn write_map_line(writer: &mut BufWriter<File>, name: &str, sizes: &FileSizes) -> Result<(), Error> {
-    writeln!(writer, "{:<length$.length$}", name, length=sizes.map_name_length)?;
+fn write_term_line(writer: &mut BufWriter<File>, term: &str, num_docs: usize, sizes: &FileSizes) -> Result<(), Error> {
+    writeln!(writer,
+        "{:<term_length$.term_length$} {:<num_docs_length$.num_docs_length$}",
+        term, num_docs.to_string(),
+        term_length = TERM_LENGTH,
+        num_docs_length = sizes.num_docs_length
+    )?;
+    Ok(())
+}
+
+// Documents are stored in deflate-compressed blocks of DOCS_PER_STORE_BLOCK, in doc id order
+fn write_store(outdir: &str, docs: &[MapRecord], indir: &str, spool: &mut StoreSpoolReader) -> Result<Vec<(usize, usize)>, Error> {
+    let store_file = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{outdir}/store"))?;
+    let mut writer = BufWriter::new(store_file);
+    let mut blocks = vec![];
+    let mut offset = 0;
+    for block_docs in docs.chunks(DOCS_PER_STORE_BLOCK) {
+        let stored_docs: Vec<StoredDoc> = block_docs.iter().map(|doc| read_stored_doc(indir, doc, spool)).collect::<Result<_, Error>>()?;
+        let length = write_store_block(&mut writer, &stored_docs)?;
+        blocks.push((offset, length));
+        offset += length;
+    }
+    Ok(blocks)
+}
+
+// The text comes from the spool the tokenizer wrote as it read each document
+fn read_stored_doc(indir: &str, doc: &MapRecord, spool: &mut StoreSpoolReader) -> Result<StoredDoc, Error> {
+    let text = spool.read_text(&doc.file_name)?;
+    Ok(StoredDoc { title: doc.title.clone(), url: format!("file://{indir}/{}", doc.file_name), text })
+}
+
+fn write_store_block(writer: &mut BufWriter<File>, docs: &[StoredDoc]) -> Result<usize, Error> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+    serde_json::to_writer(&mut encoder, docs)?;
+    let compressed = encoder.finish()?;
+    writer.write_all(&compressed)?;
+    Ok(compressed.len())
+}
+
+fn write_store_index(outdir: &str, blocks: &[(usize, usize)], sizes: &FileSizes) -> Result<(), Error> {
+    let index_file = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{outdir}/store_index"))?;
+    let mut writer = BufWriter::new(index_file);
+    for (offset, length) in blocks {
+        writeln!(writer,
+            "{:<offset_length$.offset_length$} {:<offset_length$.offset_length$}",
+            offset.to_string(), length.to_string(),
+            offset_length = sizes.store_offset_length
+        )?;
+    }
     Ok(())
 }
 
@@ -88,11 +244,111 @@ fn write_sizes(outdir: &str, sizes: &FileSizes) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn write_output_files(outdir: &str, glob_ht: &HashTable<GlobHTBucket>, map_files: &Vec<MapRecord>) -> Result<(), Error> {
-    let sizes = FileSizes::new(&glob_ht, &map_files);
-    write_sizes(&outdir, &sizes)?;
-    write_dict(&outdir, &glob_ht, &sizes)?;
-    write_post(&outdir, &glob_ht, &sizes, map_files.len())?;
-    write_map(&outdir, &map_files, &sizes)?;
+fn write_metadata(outdir: &str, metadata: &IndexMetadata) -> Result<(), Error> {
+    let metadata_file = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{outdir}/metadata"))?;
+    let mut writer = BufWriter::new(metadata_file);
+    let metadata = serde_json::to_string(metadata)?;
+    writeln!(&mut writer, "{}", metadata)?;
+    Ok(())
+}
+
+fn get_dict_metadata<T>(glob_ht: &HashTable<T>, metadata: &IndexMetadata) -> Result<IndexMetadata, Error> {
+    let used_slots = glob_ht.get_slots().iter().filter(|slot| !matches!(slot, Slot::Empty)).count();
+    let dict_load_factor = used_slots as f64 / glob_ht.get_size() as f64;
+    if dict_load_factor > MAX_LOAD_FACTOR {
+        return Err(Error::CorruptIndex(format!("dict load factor {dict_load_factor} is over {MAX_LOAD_FACTOR}")))
+    }
+    let max_dict_probes = glob_ht.iter()
+        .filter_map(|entry| glob_ht.probe_length(&entry.key))
+        .max()
+        .unwrap_or(0);
+    Ok(IndexMetadata { max_dict_probes, dict_load_factor, dict_hash_function: glob_ht.get_hash_function(), ..metadata.clone() })
+}
+
+fn write_store_files(outdir: &str, map_files: &[MapRecord], metadata: &IndexMetadata, store_spool: Option<StoreSpool>, sizes: &mut FileSizes) -> Result<(), Error> {
+    if let (Some(store_spool), Some(indir)) = (store_spool, &metadata.indir) {
+        let mut spool = store_spool.into_reader()?;
+        let blocks = write_store(outdir, map_files, indir, &mut spool)?;
+        spool.remove()?;
+        let store_length = blocks.last().map_or(0, |(offset, length)| offset + length);
+        sizes.store_offset_length = store_length.to_string().len();
+        write_store_index(outdir, &blocks, sizes)?;
+    }
+    Ok(())
+}
+
+// Failed documents have no postings and are left out of the index, so the rest are given new doc ids with no gaps, in the same order.
+// Returns the documents to write and the new doc id for each original one
+fn remove_failed_docs(map_files: &[MapRecord]) -> (Vec<MapRecord>, Vec<usize>) {
+    let mut docs = vec![];
+    let mut doc_ids = Vec::with_capacity(map_files.len());
+    for doc in map_files {
+        doc_ids.push(docs.len());
+        if doc.status == DocStatus::Indexed {
+            docs.push(MapRecord { doc_id: docs.len(), ..doc.clone() });
+        }
+    }
+    (docs, doc_ids)
+}
+
+// Returns the stats known once the index is written, the number of terms indexed and pruned
+pub fn write_output_files(outdir: &str, glob_ht: &HashTable<GlobHTBucket>, map_files: &[MapRecord], metadata: &IndexMetadata, store_spool: Option<StoreSpool>) -> Result<IndexStats, Error> {
+    let (docs, doc_ids) = remove_failed_docs(map_files);
+    let map_files = &docs;
+    let mut sizes = FileSizes::new(glob_ht, map_files, &metadata.pruning);
+    write_store_files(outdir, map_files, metadata, store_spool, &mut sizes)?;
+    write_sizes(outdir, &sizes)?;
+    write_metadata(outdir, &get_dict_metadata(glob_ht, metadata)?)?;
+    write_dict(outdir, glob_ht, &sizes, map_files.len(), &metadata.pruning)?;
+    write_post(outdir, glob_ht, &doc_ids, &sizes, map_files.len(), metadata)?;
+    write_map(outdir, map_files, &sizes)?;
+    let terms: Vec<(&str, usize)> = glob_ht.iter().filter(|entry| metadata.pruning.keeps(&entry.key, &entry.value)).map(|entry| (entry.key.as_str(), entry.value.get_num_docs())).collect();
+    let stats = IndexStats { unique_terms: terms.len(), rare_terms_pruned: glob_ht.len() - terms.len(), ..Default::default() };
+    write_terms(outdir, terms, &sizes)?;
+    Ok(stats)
+}
+
+// Writes the same files as write_output_files from runs spilled to disk while tokenizing. Postings are written as the runs
+// are merged, in term order, so only the dict's terms are held in memory rather than every posting
+pub fn write_output_files_from_runs(outdir: &str, run_paths: &[String], map_files: &[MapRecord], metadata: &IndexMetadata, store_spool: Option<StoreSpool>) -> Result<IndexStats, Error> {
+    let (docs, doc_ids) = remove_failed_docs(map_files);
+    let map_files = &docs;
+    let mut sizes = FileSizes::from_map_files(map_files);
+    let mut post_writer = PostWriter::open(outdir, &doc_ids)?;
+    let mut dict_terms: HashTable<DictTerm> = HashTable::new(GLOB_HT_SIZE);
+    let mut merger = RunMerger::open(run_paths)?;
+    let mut rare_terms_pruned = 0;
+    while let Some(entry) = merger.next_term()? {
+        if !metadata.pruning.keeps(&entry.key, &entry.value) {
+            rare_terms_pruned += 1;
+            continue
+        }
+        let dict_term = DictTerm::new(&entry.value, post_writer.line, map_files.len());
+        post_writer.write_postings(&entry.value, &sizes, map_files.len(), metadata.postings_order)?;
+        dict_terms.insert(&entry.key, dict_term);
+    }
+    sizes.num_dict_lines = dict_terms.get_size();
+    sizes.post_line_start_length = post_writer.line.to_string().len();
+    sizes.num_docs_length = dict_terms.iter().map(|entry| entry.value.num_docs).max().unwrap_or(0).to_string().len();
+    sizes.num_term_lines = dict_terms.len();
+    write_store_files(outdir, map_files, metadata, store_spool, &mut sizes)?;
+    write_sizes(outdir, &sizes)?;
+    write_metadata(outdir, &get_dict_metadata(&dict_terms, metadata)?)?;
+    write_dict_terms(outdir, &dict_terms, &sizes)?;
+    write_map(outdir, map_files, &sizes)?;
+    let terms = dict_terms.iter().map(|entry| (entry.key.as_str(), entry.value.num_docs)).collect();
+    write_terms(outdir, terms, &sizes)?;
+    Ok(IndexStats { unique_terms: dict_terms.len(), rare_terms_pruned, ..Default::default() })
+}
+
+// Writes the stats as json to the stats file, along with the size of each index file written
+pub fn write_stats(outdir: &str, stats: &IndexStats) -> Result<(), Error> {
+    let file_sizes = INDEX_FILES.iter()
+        .filter_map(|file| Some((file.to_string(), fs::metadata(format!("{outdir}/{file}")).ok()?.len())))
+        .collect();
+    let stats_file = OpenOptions::new().write(true).create(true).truncate(true).open(format!("{outdir}/stats"))?;
+    let mut writer = BufWriter::new(stats_file);
+    let stats = serde_json::to_string(&IndexStats { file_sizes, ..stats.clone() })?;
+    writeln!(&mut writer, "{}", stats)?;
     Ok(())
 }